@@ -0,0 +1,167 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// What nixos-rebuild (or switch-to-configuration) does with the new system
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum RebuildAction {
+    /// Activate and make it the boot default
+    #[default]
+    Switch,
+    /// Make it the boot default without activating
+    Boot,
+    /// Activate without adding a boot entry
+    Test,
+    /// Show which units would be stopped, restarted, started or reloaded
+    DryActivate,
+    /// Only build the system
+    Build,
+    /// Only show what would be built or downloaded
+    DryBuild,
+}
+
+impl RebuildAction {
+    /// Argument for nixos-rebuild and switch-to-configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RebuildAction::Switch => "switch",
+            RebuildAction::Boot => "boot",
+            RebuildAction::Test => "test",
+            RebuildAction::DryActivate => "dry-activate",
+            RebuildAction::Build => "build",
+            RebuildAction::DryBuild => "dry-build",
+        }
+    }
+
+    /// Whether the system profile gets a new generation
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, RebuildAction::Switch | RebuildAction::Boot)
+    }
+
+    /// Whether the running system is changed
+    pub fn activates(&self) -> bool {
+        matches!(self, RebuildAction::Switch | RebuildAction::Test)
+    }
+}
+
+impl std::fmt::Display for RebuildAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Unit changes reported by `switch-to-configuration dry-activate`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DryActivation {
+    pub stop: Vec<String>,
+    pub restart: Vec<String>,
+    pub start: Vec<String>,
+    pub reload: Vec<String>,
+}
+
+impl DryActivation {
+    /// Collect the "would stop/restart/start/reload the following units" lines
+    pub fn parse(output: &str) -> Self {
+        let stripped = strip_ansi_escapes::strip(output.as_bytes());
+        let output = String::from_utf8_lossy(&stripped);
+
+        let mut changes = Self::default();
+        for line in output.lines() {
+            let line = line.trim();
+            if line == "would restart systemd" {
+                changes.restart.push("systemd".to_string());
+                continue;
+            }
+
+            let Some((verb, units)) = line
+                .strip_prefix("would ")
+                .and_then(|rest| rest.split_once(" the following units: "))
+            else {
+                continue;
+            };
+            let list = match verb {
+                "stop" => &mut changes.stop,
+                "restart" => &mut changes.restart,
+                "start" => &mut changes.start,
+                "reload" => &mut changes.reload,
+                _ => continue,
+            };
+            list.extend(
+                units
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|u| !u.is_empty())
+                    .map(str::to_string),
+            );
+        }
+        changes
+    }
+
+    /// One line per kind of change, e.g. `would restart: nginx.service, sshd.service`
+    pub fn summary_lines(&self) -> Vec<String> {
+        let lines: Vec<String> = [
+            ("stop", &self.stop),
+            ("restart", &self.restart),
+            ("start", &self.start),
+            ("reload", &self.reload),
+        ]
+        .into_iter()
+        .filter(|(_, units)| !units.is_empty())
+        .map(|(verb, units)| format!("would {}: {}", verb, units.join(", ")))
+        .collect();
+
+        if lines.is_empty() {
+            vec!["no unit changes".to_string()]
+        } else {
+            lines
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unit_changes() {
+        let output = "\
+building the system configuration...
+would stop the following units: old.service
+would restart systemd
+would restart the following units: nginx.service, sshd.service
+would start the following units: new.service,
+would reload the following units: dbus.service
+would frobnicate the following units: other.service
+";
+        let changes = DryActivation::parse(output);
+
+        assert_eq!(changes.stop, ["old.service"]);
+        assert_eq!(
+            changes.restart,
+            ["systemd", "nginx.service", "sshd.service"]
+        );
+        assert_eq!(changes.start, ["new.service"]);
+        assert_eq!(changes.reload, ["dbus.service"]);
+        assert_eq!(
+            changes.summary_lines(),
+            [
+                "would stop: old.service",
+                "would restart: systemd, nginx.service, sshd.service",
+                "would start: new.service",
+                "would reload: dbus.service",
+            ]
+        );
+    }
+
+    #[test]
+    fn strips_colors_before_parsing() {
+        let output = "\x1b[1mwould restart the following units: nginx.service\x1b[0m\n";
+        assert_eq!(DryActivation::parse(output).restart, ["nginx.service"]);
+    }
+
+    #[test]
+    fn no_changes() {
+        let changes = DryActivation::parse("activating the configuration...\n");
+        assert!(changes.restart.is_empty());
+        assert_eq!(changes.summary_lines(), ["no unit changes"]);
+    }
+}
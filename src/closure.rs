@@ -0,0 +1,354 @@
+use anyhow::{Context, Result, bail};
+use ssh2::{Channel, Session};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+use crate::nix_log::{LOG_FORMAT_ARGS, NixLog};
+use crate::progress::{NixProgress, ProgressUpdate, UpdatePhase};
+use crate::ssh_executor::{Sudo, read_nonblocking, send_output_line};
+use crate::updater::UpdateResult;
+
+/// Line sent after the sudo password, see `copy_closure`
+const STDIN_MARKER: &str = "nix-deploy-nar-follows";
+
+/// Build system toplevels once on this machine (or a build host) and push
+/// the closures to the targets instead of running nixos-rebuild on each host
+#[derive(Debug, Clone)]
+pub struct LocalBuild {
+    pub flake: String,
+    /// Remote store to build on, e.g. `builder` or `ssh-ng://builder`
+    pub build_host: Option<String>,
+}
+
+impl LocalBuild {
+    fn installable(&self, attr: &str) -> String {
+        format!(
+            "{}#nixosConfigurations.\"{}\".config.system.build.toplevel",
+            self.flake, attr
+        )
+    }
+
+    fn build_store(&self) -> Option<String> {
+        self.build_host.as_ref().map(|host| {
+            if host.contains("://") {
+                host.clone()
+            } else {
+                format!("ssh-ng://{}", host)
+            }
+        })
+    }
+
+    fn build_command(&self, attr: &str, extra_args: &[&str]) -> Command {
+        let mut build = nix_command();
+        build.arg("build").args(LOG_FORMAT_ARGS).args(extra_args);
+        if let Some(store) = self.build_store() {
+            build.args(["--eval-store", "auto", "--store", &store]);
+        }
+        build.arg(self.installable(attr));
+        build
+    }
+
+    /// Build the toplevel of `attr` and return its store path
+    ///
+    /// With a build host, the result is copied back so it can be pushed from here.
+    pub fn build_toplevel(
+        &self,
+        attr: &str,
+        progress_tx: &mpsc::Sender<ProgressUpdate>,
+        result: &mut UpdateResult,
+    ) -> Result<String> {
+        let build = self.build_command(attr, &["--no-link", "--print-out-paths"]);
+
+        let phase = UpdatePhase::Building {
+            progress: NixProgress::default(),
+        };
+        let stdout = run_local_streaming(build, progress_tx, &phase, result)?;
+
+        let toplevel = stdout
+            .lines()
+            .last()
+            .map(|line| line.trim().to_string())
+            .filter(|line| line.starts_with('/'))
+            .ok_or_else(|| anyhow::anyhow!("nix build did not print an output path"))?;
+
+        if let Some(store) = self.build_store() {
+            let mut fetch = nix_command();
+            fetch.arg("copy").args(LOG_FORMAT_ARGS).args([
+                "--no-check-sigs",
+                "--from",
+                &store,
+                &toplevel,
+            ]);
+            run_local_streaming(fetch, progress_tx, &phase, result)?;
+        }
+
+        Ok(toplevel)
+    }
+
+    /// Show what building the toplevel of `attr` would build or download
+    pub fn dry_build(
+        &self,
+        attr: &str,
+        progress_tx: &mpsc::Sender<ProgressUpdate>,
+        result: &mut UpdateResult,
+    ) -> Result<()> {
+        let build = self.build_command(attr, &["--dry-run"]);
+        let phase = UpdatePhase::Building {
+            progress: NixProgress::default(),
+        };
+        run_local_streaming(build, progress_tx, &phase, result)?;
+        Ok(())
+    }
+}
+
+fn nix_command() -> Command {
+    let mut command = Command::new("nix");
+    command.args(["--extra-experimental-features", "nix-command flakes"]);
+    command
+}
+
+fn describe(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run a local command, forwarding its stderr line by line, and return stdout
+fn run_local_streaming(
+    mut command: Command,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    phase: &UpdatePhase,
+    result: &mut UpdateResult,
+) -> Result<String> {
+    let description = describe(&command);
+    let started = Instant::now();
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", description))?;
+
+    let mut stdout_pipe = child.stdout.take().expect("stdout is piped");
+    let stdout_reader = std::thread::spawn(move || {
+        let mut stdout = String::new();
+        let _ = stdout_pipe.read_to_string(&mut stdout);
+        stdout
+    });
+
+    let mut log = String::new();
+    let mut nix_log = NixLog::default();
+    let stderr = child.stderr.take().expect("stderr is piped");
+    for line in BufReader::new(stderr).lines() {
+        let line = line?;
+        log.push_str(&line);
+        log.push('\n');
+        let trimmed = line.trim();
+        if !trimmed.is_empty() {
+            send_output_line(progress_tx, &result.hostname, phase, &mut nix_log, trimmed);
+        }
+    }
+
+    let status = child.wait()?;
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let exit_code = status.code().unwrap_or(-1);
+    result.record_command(&description, &log, exit_code, started);
+
+    if !status.success() {
+        bail!("{} failed with exit code: {}", description, exit_code);
+    }
+
+    Ok(stdout)
+}
+
+/// Copy the closure of `store_path` into the host's store over `sess`
+///
+/// Only paths missing on the host are exported, in the topological order of
+/// `nix-store --query --requisites` so that `--import` finds their references.
+pub fn copy_closure(
+    sess: &Session,
+    store_path: &str,
+    sudo: &Sudo,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<()> {
+    let hostname = result.hostname.clone();
+    let started = Instant::now();
+
+    let requisites = Command::new("nix-store")
+        .args(["--query", "--requisites", store_path])
+        .output()
+        .context("Failed to run nix-store --query")?;
+    if !requisites.status.success() {
+        bail!(
+            "nix-store --query failed: {}",
+            String::from_utf8_lossy(&requisites.stderr).trim()
+        );
+    }
+    let paths: Vec<String> = String::from_utf8_lossy(&requisites.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+
+    // Ask the host which paths it doesn't have yet
+    let mut channel = sess.channel_session()?;
+    channel.exec("xargs -r nix-store --check-validity --print-invalid")?;
+    channel.write_all(paths.join("\n").as_bytes())?;
+    channel.send_eof()?;
+    let mut invalid = String::new();
+    channel.read_to_string(&mut invalid)?;
+    channel.wait_close()?;
+    if channel.exit_status()? != 0 {
+        bail!("Failed to query valid store paths on {}", hostname);
+    }
+
+    let invalid: HashSet<&str> = invalid.lines().collect();
+    let missing: Vec<&String> = paths
+        .iter()
+        .filter(|p| invalid.contains(&p.as_str()))
+        .collect();
+
+    let summary = format!(
+        "{} of {} paths missing on {}",
+        missing.len(),
+        paths.len(),
+        hostname
+    );
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.clone(),
+        phase: UpdatePhase::CopyingClosure {
+            progress: format!("0/{} paths", missing.len()),
+        },
+        output_line: Some(summary.clone()),
+    });
+
+    if missing.is_empty() {
+        result.record_command("copy closure", &summary, 0, started);
+        return Ok(());
+    }
+
+    let mut export = Command::new("nix-store")
+        .arg("--export")
+        .args(&missing)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run nix-store --export")?;
+    let mut export_stdout = export.stdout.take().expect("stdout is piped");
+    // Read on its own thread so that warnings can't fill the pipe while the
+    // export waits for its stdout to be read
+    let mut export_stderr_pipe = export.stderr.take().expect("stderr is piped");
+    let export_stderr_reader = std::thread::spawn(move || {
+        let mut stderr = String::new();
+        let _ = export_stderr_pipe.read_to_string(&mut stderr);
+        stderr
+    });
+
+    let mut channel = sess.channel_session()?;
+    // Importing unsigned paths needs a trusted user, so run it as root
+    match sudo.password_line() {
+        // sudo only reads the password line if it needs it (not with a
+        // NOPASSWD rule), so skip stdin up to a marker line before importing
+        // to keep the password out of the NAR stream either way
+        Some(line) => {
+            let import_cmd = format!(
+                "while IFS= read -r line && [ \"$line\" != {0} ]; do :; done; \
+                 exec nix-store --import > /dev/null",
+                STDIN_MARKER
+            );
+            channel.exec(&sudo.wrap(&import_cmd, false, false))?;
+            channel.write_all(line.as_bytes())?;
+            channel.write_all(format!("{}\n", STDIN_MARKER).as_bytes())?;
+        }
+        None => channel.exec(&sudo.wrap("nix-store --import > /dev/null", false, false))?,
+    }
+
+    let mut buffer = [0u8; 64 * 1024];
+    let mut import_stderr = Vec::new();
+    let mut copied: u64 = 0;
+    let mut last_report: u64 = 0;
+    loop {
+        let n = export_stdout.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        channel.write_all(&buffer[..n])?;
+        drain_stderr(sess, &mut channel, &mut import_stderr)?;
+        copied += n as u64;
+
+        if copied - last_report >= 4 * 1024 * 1024 {
+            last_report = copied;
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.clone(),
+                phase: UpdatePhase::CopyingClosure {
+                    progress: format!(
+                        "{} paths, {:.1} MiB",
+                        missing.len(),
+                        copied as f64 / (1024.0 * 1024.0)
+                    ),
+                },
+                output_line: None,
+            });
+        }
+    }
+
+    let export_status = export.wait()?;
+    let export_stderr = export_stderr_reader.join().unwrap_or_default();
+    channel.send_eof()?;
+    channel.stderr().read_to_end(&mut import_stderr)?;
+    let import_stderr = String::from_utf8_lossy(&import_stderr);
+    channel.wait_close()?;
+    let import_status = channel.exit_status()?;
+
+    let log = format!(
+        "{}\nCopied {:.1} MiB\n{}",
+        summary,
+        copied as f64 / (1024.0 * 1024.0),
+        import_stderr
+    );
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.clone(),
+        phase: UpdatePhase::CopyingClosure {
+            progress: format!("{:.1} MiB", copied as f64 / (1024.0 * 1024.0)),
+        },
+        output_line: Some(format!(
+            "Copied {:.1} MiB",
+            copied as f64 / (1024.0 * 1024.0)
+        )),
+    });
+    result.record_command("copy closure", &log, import_status, started);
+
+    if !export_status.success() {
+        bail!("nix-store --export failed: {}", export_stderr.trim());
+    }
+    if import_status != 0 {
+        bail!(
+            "nix-store --import failed with exit code {}: {}",
+            import_status,
+            import_stderr.trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Read what the import wrote to stderr so far
+///
+/// stdout and stderr share the channel's window, so stderr that is left
+/// unread can stall the import and with it the writes of the closure.
+fn drain_stderr(sess: &Session, channel: &mut Channel, stderr: &mut Vec<u8>) -> Result<()> {
+    let mut buffer = [0u8; 4096];
+    loop {
+        match read_nonblocking(sess, &mut channel.stderr(), &mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => stderr.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
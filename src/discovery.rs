@@ -0,0 +1,364 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::host::Host;
+use crate::ssh_config::SshConfig;
+
+/// A source of hosts for the server selector
+pub trait Discovery {
+    fn name(&self) -> &'static str;
+
+    fn discover(&self) -> Result<Vec<Host>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Online peers from `tailscale status`
+    Tailscale,
+    /// Hosts listed in the inventory file
+    Inventory,
+    /// Concrete `Host` entries of ~/.ssh/config
+    SshConfig,
+    /// `nixosConfigurations` attribute names of a local flake
+    Flake,
+}
+
+/// Host name pattern: a glob (`*`, `?`) or a regex wrapped in slashes (`/^web-\d+$/`)
+#[derive(Debug, Clone)]
+pub enum HostPattern {
+    Glob(String),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        match pattern
+            .strip_prefix('/')
+            .and_then(|rest| rest.strip_suffix('/'))
+        {
+            Some(regex) => Ok(HostPattern::Regex(
+                Regex::new(regex).with_context(|| format!("Invalid host pattern {}", pattern))?,
+            )),
+            None => Ok(HostPattern::Glob(pattern.to_string())),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            HostPattern::Glob(glob) => glob_matches(glob.as_bytes(), name.as_bytes()),
+            HostPattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Match `text` against a pattern with `*` and `?` wildcards
+pub fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_matches(&pattern[1..], text)
+                || (!text.is_empty() && glob_matches(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_matches(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => glob_matches(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Include/exclude rules for discovered host names
+///
+/// A host is kept if it matches any include pattern (or there are none) and
+/// no exclude pattern.
+#[derive(Debug, Clone, Default)]
+pub struct HostFilter {
+    pub include: Vec<HostPattern>,
+    pub exclude: Vec<HostPattern>,
+}
+
+impl HostFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: include
+                .iter()
+                .map(|p| HostPattern::parse(p))
+                .collect::<Result<_>>()?,
+            exclude: exclude
+                .iter()
+                .map(|p| HostPattern::parse(p))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(name)))
+            && !self.exclude.iter().any(|p| p.matches(name))
+    }
+}
+
+/// Run every backend, merge hosts with the same name and apply the filter
+///
+/// Backends later in the list override values reported by earlier ones.
+pub fn discover_hosts(backends: &[Box<dyn Discovery>], filter: &HostFilter) -> Result<Vec<Host>> {
+    let mut hosts: Vec<Host> = Vec::new();
+
+    for backend in backends {
+        let discovered = backend
+            .discover()
+            .with_context(|| format!("{} discovery failed", backend.name()))?;
+
+        for host in discovered {
+            match hosts.iter_mut().find(|h| h.name == host.name) {
+                Some(existing) => existing.merge(host),
+                None => hosts.push(host),
+            }
+        }
+    }
+
+    hosts.retain(|h| filter.matches(&h.name));
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(hosts)
+}
+
+#[derive(Debug, Deserialize)]
+struct TailscaleStatus {
+    #[serde(rename = "Peer")]
+    peers: HashMap<String, TailscalePeer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TailscalePeer {
+    #[serde(rename = "HostName")]
+    host_name: String,
+    #[serde(rename = "TailscaleIPs")]
+    ips: Vec<String>,
+    #[serde(rename = "Online")]
+    online: bool,
+}
+
+pub struct TailscaleDiscovery {
+    /// Applied to peer names before merging; historically `nix*`
+    pub filter: HostFilter,
+}
+
+impl Discovery for TailscaleDiscovery {
+    fn name(&self) -> &'static str {
+        "tailscale"
+    }
+
+    fn discover(&self) -> Result<Vec<Host>> {
+        let output = Command::new("tailscale")
+            .arg("status")
+            .arg("--json")
+            .output()
+            .context("Failed to execute tailscale command")?;
+
+        let status: TailscaleStatus = serde_json::from_slice(&output.stdout)
+            .context("Failed to parse tailscale status JSON")?;
+
+        let mut hosts = Vec::new();
+        for (_, peer) in status.peers {
+            if self.filter.matches(&peer.host_name) && !peer.ips.is_empty() && peer.online {
+                let ip = peer.ips[0].clone();
+                hosts.push(Host::new(peer.host_name).with_address(ip));
+            }
+        }
+
+        Ok(hosts)
+    }
+}
+
+pub struct SshConfigDiscovery {
+    pub path: PathBuf,
+}
+
+impl Discovery for SshConfigDiscovery {
+    fn name(&self) -> &'static str {
+        "ssh-config"
+    }
+
+    fn discover(&self) -> Result<Vec<Host>> {
+        let config = SshConfig::load(&self.path)?;
+
+        let mut hosts: Vec<Host> = config.host_aliases().map(Host::new).collect();
+        config.apply(&mut hosts);
+        Ok(hosts)
+    }
+}
+
+pub struct FlakeDiscovery {
+    pub flake: String,
+}
+
+impl Discovery for FlakeDiscovery {
+    fn name(&self) -> &'static str {
+        "flake"
+    }
+
+    fn discover(&self) -> Result<Vec<Host>> {
+        let output = Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command flakes"])
+            .arg("eval")
+            .arg("--json")
+            .arg(format!("{}#nixosConfigurations", self.flake))
+            .args(["--apply", "builtins.attrNames"])
+            .output()
+            .context("Failed to execute nix eval")?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "nix eval failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        let names: Vec<String> = serde_json::from_slice(&output.stdout)
+            .context("Failed to parse nixosConfigurations attribute names")?;
+
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let mut host = Host::new(name.clone());
+                host.flake_attr = Some(name);
+                host
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeDiscovery {
+        hosts: Vec<Host>,
+        fail: bool,
+    }
+
+    impl Discovery for FakeDiscovery {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn discover(&self) -> Result<Vec<Host>> {
+            if self.fail {
+                anyhow::bail!("backend unavailable");
+            }
+            Ok(self.hosts.clone())
+        }
+    }
+
+    fn backend(hosts: Vec<Host>) -> Box<dyn Discovery> {
+        Box::new(FakeDiscovery { hosts, fail: false })
+    }
+
+    fn names(hosts: &[Host]) -> Vec<&str> {
+        hosts.iter().map(|h| h.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merges_duplicate_hosts_with_later_backends_winning() {
+        let mut second = Host::new("nixweb").with_address("10.0.0.2");
+        second.port = Some(2222);
+        second.tags = vec!["web".to_string()];
+
+        let mut first = Host::new("nixweb").with_address("100.64.0.1");
+        first.user = Some("deploy".to_string());
+        first.tags = vec!["web".to_string(), "prod".to_string()];
+
+        let backends = vec![
+            backend(vec![first, Host::new("nixdb")]),
+            backend(vec![second]),
+        ];
+        let hosts = discover_hosts(&backends, &HostFilter::default()).unwrap();
+
+        assert_eq!(names(&hosts), ["nixdb", "nixweb"]);
+        let web = &hosts[1];
+        assert_eq!(web.address.as_deref(), Some("10.0.0.2"));
+        assert_eq!(web.port, Some(2222));
+        assert_eq!(web.user.as_deref(), Some("deploy"));
+        assert_eq!(web.tags, ["web", "prod"]);
+    }
+
+    #[test]
+    fn applies_include_and_exclude_patterns() {
+        let backends = vec![backend(
+            ["nixweb", "nix-test", "web-01", "web-x", "db"]
+                .into_iter()
+                .map(Host::new)
+                .collect(),
+        )];
+        let filter = HostFilter::new(
+            &["nix*".to_string(), r"/^web-\d+$/".to_string()],
+            &["nix-test*".to_string()],
+        )
+        .unwrap();
+
+        let hosts = discover_hosts(&backends, &filter).unwrap();
+        assert_eq!(names(&hosts), ["nixweb", "web-01"]);
+    }
+
+    #[test]
+    fn empty_include_keeps_everything_not_excluded() {
+        let filter = HostFilter::new(&[], &["db*".to_string()]).unwrap();
+        assert!(filter.matches("anything"));
+        assert!(!filter.matches("db-01"));
+    }
+
+    #[test]
+    fn failing_backend_fails_discovery_with_its_name() {
+        let backends = vec![
+            backend(vec![Host::new("nixweb")]),
+            Box::new(FakeDiscovery {
+                hosts: Vec::new(),
+                fail: true,
+            }) as Box<dyn Discovery>,
+        ];
+
+        let err = discover_hosts(&backends, &HostFilter::default()).unwrap_err();
+        assert_eq!(err.to_string(), "fake discovery failed");
+        assert_eq!(err.root_cause().to_string(), "backend unavailable");
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        let cases = [
+            ("nix*", "nixweb", true),
+            ("nix*", "nix", true),
+            ("nix*", "webnix", false),
+            ("*-01", "db-01", true),
+            ("web-??", "web-01", true),
+            ("web-??", "web-1", false),
+            ("*a*b", "xaxxb", true),
+            ("*a*b", "xaxxbc", false),
+            ("", "", true),
+            ("exact", "exact", true),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_matches(pattern.as_bytes(), text.as_bytes()),
+                expected,
+                "{pattern} vs {text}"
+            );
+        }
+    }
+
+    #[test]
+    fn host_pattern_regex_in_slashes() {
+        let regex = HostPattern::parse(r"/^web-\d+$/").unwrap();
+        assert!(matches!(regex, HostPattern::Regex(_)));
+        assert!(regex.matches("web-12"));
+        assert!(!regex.matches("web-x"));
+
+        let glob = HostPattern::parse("/srv*").unwrap();
+        assert!(matches!(glob, HostPattern::Glob(_)));
+        assert!(glob.matches("/srv1"));
+
+        assert!(HostPattern::parse("/(/").is_err());
+    }
+}
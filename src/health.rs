@@ -0,0 +1,164 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::discovery::HostPattern;
+use crate::host::Host;
+use crate::ssh_executor::shell_quote;
+
+/// Checks that must pass on a host after activation before it counts as updated
+///
+/// ```toml
+/// [health]
+/// rollback = true
+/// retries = 3
+/// interval = 5
+///
+/// [[health.checks]]
+/// type = "no-failed-units"
+///
+/// [[health.checks]]
+/// type = "unit"
+/// unit = "nginx.service"
+/// tags = ["web"]
+///
+/// [[health.checks]]
+/// type = "http"
+/// url = "http://localhost:8080/healthz"
+/// hosts = ["web-*"]
+///
+/// [[health.checks]]
+/// type = "tcp"
+/// port = 5432
+/// tags = ["db"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthSettings {
+    /// Switch back to the previous generation if a check keeps failing
+    pub rollback: bool,
+    /// Attempts per check before it counts as failed
+    pub retries: u32,
+    /// Seconds between attempts
+    pub interval: u64,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        Self {
+            rollback: false,
+            retries: 3,
+            interval: 5,
+            checks: Vec::new(),
+        }
+    }
+}
+
+impl HealthSettings {
+    /// Checks that apply to `host`, in configuration order
+    pub fn checks_for(&self, host: &Host) -> Vec<&HealthCheck> {
+        self.checks.iter().filter(|c| c.applies_to(host)).collect()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+}
+
+/// A single check and the hosts it applies to
+///
+/// Without `hosts` and `tags` the check runs on every host. Otherwise the host
+/// name must match one of the `hosts` patterns or the host must carry one of
+/// the `tags`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheck {
+    #[serde(flatten)]
+    pub kind: CheckKind,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum CheckKind {
+    /// `systemctl is-active` for a unit
+    Unit { unit: String },
+    /// `systemctl --failed` lists nothing
+    NoFailedUnits,
+    /// A URL fetched with curl on the host answers with a 2xx status
+    Http {
+        url: String,
+        #[serde(default = "default_check_timeout")]
+        timeout: u64,
+    },
+    /// A TCP port accepts connections, checked from the host itself
+    Tcp {
+        port: u16,
+        #[serde(default = "default_tcp_host")]
+        host: String,
+        #[serde(default = "default_check_timeout")]
+        timeout: u64,
+    },
+}
+
+fn default_check_timeout() -> u64 {
+    10
+}
+
+fn default_tcp_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+impl HealthCheck {
+    fn applies_to(&self, host: &Host) -> bool {
+        if self.hosts.is_empty() && self.tags.is_empty() {
+            return true;
+        }
+
+        self.hosts
+            .iter()
+            .filter_map(|p| HostPattern::parse(p).ok())
+            .any(|p| p.matches(&host.name))
+            || host.tags.iter().any(|t| self.tags.contains(t))
+    }
+
+    pub fn description(&self) -> String {
+        match &self.kind {
+            CheckKind::Unit { unit } => format!("unit {} is active", unit),
+            CheckKind::NoFailedUnits => "no failed units".to_string(),
+            CheckKind::Http { url, .. } => format!("{} returns 2xx", url),
+            CheckKind::Tcp { port, host, .. } => format!("{}:{} accepts connections", host, port),
+        }
+    }
+
+    /// Shell command run on the host; the check passes if it exits with 0
+    pub fn command(&self) -> String {
+        match &self.kind {
+            CheckKind::Unit { unit } => {
+                format!("systemctl is-active {}", shell_quote(unit))
+            }
+            CheckKind::NoFailedUnits => {
+                "failed=$(systemctl list-units --failed --no-legend --plain); \
+                 echo \"$failed\"; test -z \"$failed\""
+                    .to_string()
+            }
+            CheckKind::Http { url, timeout } => format!(
+                "status=$(curl -sS -o /dev/null -w '%{{http_code}}' --max-time {} {}); \
+                 echo \"HTTP $status\"; case $status in 2??) ;; *) exit 1 ;; esac",
+                timeout,
+                shell_quote(url)
+            ),
+            CheckKind::Tcp {
+                port,
+                host,
+                timeout,
+            } => format!(
+                "timeout {} bash -c {}",
+                timeout,
+                shell_quote(&format!("</dev/tcp/{}/{}", host, port))
+            ),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::discovery::HostPattern;
+use crate::paths::state_dir;
+use crate::run_log::RunParameters;
+use crate::updater::{Operation, UpdateResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failed,
+    Skipped,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // pad, so the table columns line up
+        f.pad(match self {
+            Outcome::Success => "success",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+        })
+    }
+}
+
+/// One host's part of a run, stored as a line of `history.jsonl`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub started_at: DateTime<Utc>,
+    pub hostname: String,
+    /// Local user who started the run
+    pub user: Option<String>,
+    pub operation: Operation,
+    pub action: String,
+    pub outcome: Outcome,
+    pub failure_reason: Option<String>,
+    pub git_rev_before: Option<String>,
+    pub git_rev_after: Option<String>,
+    pub generation: Option<u64>,
+    pub duration_secs: f64,
+    /// Run directory with the logs, see `RunLog`
+    pub run_dir: Option<PathBuf>,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        parameters: &RunParameters,
+        result: &UpdateResult,
+        failure_reason: Option<String>,
+        duration_secs: f64,
+        run_dir: Option<PathBuf>,
+    ) -> Self {
+        let outcome = if result.success {
+            Outcome::Success
+        } else if result.skipped {
+            Outcome::Skipped
+        } else {
+            Outcome::Failed
+        };
+
+        Self {
+            started_at: parameters.started_at,
+            hostname: result.hostname.clone(),
+            user: parameters.user.clone(),
+            operation: parameters.operation,
+            action: parameters.action.clone(),
+            outcome,
+            failure_reason: failure_reason.filter(|_| outcome == Outcome::Failed),
+            git_rev_before: result.git_rev_before.clone(),
+            git_rev_after: result.git_rev_after.clone(),
+            generation: result.generation,
+            duration_secs,
+            run_dir,
+        }
+    }
+
+    /// `deploy (switch)`, `rollback to 41`
+    pub fn operation_label(&self) -> String {
+        match self.operation {
+            Operation::Deploy | Operation::Plan => format!("deploy ({})", self.action),
+            Operation::Rollback {
+                generation: Some(generation),
+            } => format!("rollback to {}", generation),
+            Operation::Rollback { generation: None } => "rollback".to_string(),
+        }
+    }
+
+    /// Short revisions, `abc1234 -> def5678` if the pull changed anything
+    pub fn git_label(&self) -> String {
+        let short = |rev: &String| rev.chars().take(7).collect::<String>();
+        match (&self.git_rev_before, &self.git_rev_after) {
+            (Some(before), Some(after)) if before != after => {
+                format!("{} -> {}", short(before), short(after))
+            }
+            (_, Some(rev)) | (Some(rev), None) => short(rev),
+            (None, None) => "-".to_string(),
+        }
+    }
+
+    pub fn started_label(&self) -> String {
+        self.started_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    }
+
+    pub fn duration_label(&self) -> String {
+        let secs = self.duration_secs.round() as u64;
+        if secs >= 60 {
+            format!("{}m{:02}s", secs / 60, secs % 60)
+        } else {
+            format!("{}s", secs)
+        }
+    }
+}
+
+/// `$XDG_STATE_HOME/nix-deploy/history.jsonl`
+pub fn history_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("history.jsonl"))
+}
+
+/// Append the entries of a finished run
+pub fn append(entries: &[HistoryEntry]) -> Result<()> {
+    let path = history_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+
+    // One write per run, so runs finishing at the same time don't interleave
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Entries matching the filter, newest first
+///
+/// Lines that can't be parsed, e.g. from an interrupted write, are skipped.
+pub fn load(host: Option<&HostPattern>, failed_only: bool) -> Result<Vec<HistoryEntry>> {
+    let path = history_path()?;
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+
+    let mut entries: Vec<HistoryEntry> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(line).ok())
+        .filter(|entry| host.is_none_or(|pattern| pattern.matches(&entry.hostname)))
+        .filter(|entry| !failed_only || entry.outcome == Outcome::Failed)
+        .collect();
+    // Stable, so hosts of the same run keep their order
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.started_at));
+    Ok(entries)
+}
+
+pub fn print_history_table(entries: &[HistoryEntry]) {
+    let host_width = entries
+        .iter()
+        .map(|e| e.hostname.len())
+        .max()
+        .unwrap_or(4)
+        .max(4);
+    let operation_width = entries
+        .iter()
+        .map(|e| e.operation_label().len())
+        .max()
+        .unwrap_or(9)
+        .max(9);
+    println!(
+        "{:<16}  {:<host_width$}  {:<operation_width$}  {:<7}  {:<18}  {:<10}  {:<8}  {:<10}  REASON",
+        "STARTED", "HOST", "OPERATION", "OUTCOME", "GIT", "GENERATION", "DURATION", "USER"
+    );
+
+    for entry in entries {
+        println!(
+            "{:<16}  {:<host_width$}  {:<operation_width$}  {:<7}  {:<18}  {:<10}  {:<8}  {:<10}  {}",
+            entry.started_label(),
+            entry.hostname,
+            entry.operation_label(),
+            entry.outcome,
+            entry.git_label(),
+            entry
+                .generation
+                .map(|g| g.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            entry.duration_label(),
+            entry.user.as_deref().unwrap_or("-"),
+            entry.failure_reason.as_deref().unwrap_or("")
+        );
+    }
+}
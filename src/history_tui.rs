@@ -0,0 +1,188 @@
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+};
+
+use crate::history::{HistoryEntry, Outcome};
+
+/// Browser for past deployments, filtered by host name while typing
+pub struct HistoryView {
+    entries: Vec<HistoryEntry>,
+    /// Substring of the host name, case-insensitive
+    filter: String,
+    failed_only: bool,
+    state: ListState,
+}
+
+impl HistoryView {
+    pub fn new(entries: Vec<HistoryEntry>) -> Self {
+        let mut state = ListState::default();
+        if !entries.is_empty() {
+            state.select(Some(0));
+        }
+        Self {
+            entries,
+            filter: String::new(),
+            failed_only: false,
+            state,
+        }
+    }
+
+    fn visible(&self) -> Vec<&HistoryEntry> {
+        let filter = self.filter.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|entry| entry.hostname.to_lowercase().contains(&filter))
+            .filter(|entry| !self.failed_only || entry.outcome == Outcome::Failed)
+            .collect()
+    }
+
+    /// Keep the selection within the entries left after filtering
+    fn clamp_selection(&mut self) {
+        let count = self.visible().len();
+        self.state.select(match self.state.selected() {
+            _ if count == 0 => None,
+            Some(i) => Some(i.min(count - 1)),
+            None => Some(0),
+        });
+    }
+
+    fn next(&mut self) {
+        let count = self.visible().len();
+        if count > 0 {
+            let i = self.state.selected().map_or(0, |i| (i + 1) % count);
+            self.state.select(Some(i));
+        }
+    }
+
+    fn previous(&mut self) {
+        let count = self.visible().len();
+        if count > 0 {
+            let i = self
+                .state
+                .selected()
+                .map_or(0, |i| if i == 0 { count - 1 } else { i - 1 });
+            self.state.select(Some(i));
+        }
+    }
+
+    pub fn render(&mut self, frame: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(5), Constraint::Length(9)])
+            .split(frame.area());
+
+        let visible = self.visible();
+        let items: Vec<ListItem> = visible
+            .iter()
+            .map(|entry| {
+                let color = match entry.outcome {
+                    Outcome::Success => Color::Green,
+                    Outcome::Failed => Color::Red,
+                    Outcome::Skipped => Color::DarkGray,
+                };
+                ListItem::new(format!(
+                    "{}  {}  {}  {}",
+                    entry.started_label(),
+                    entry.hostname,
+                    entry.operation_label(),
+                    entry.outcome
+                ))
+                .style(Style::default().fg(color))
+            })
+            .collect();
+
+        let title = format!(
+            "Deployment History ({} of {}) [host filter: {}]{} [Tab: failed only, Esc: quit]",
+            visible.len(),
+            self.entries.len(),
+            self.filter,
+            if self.failed_only { " [failed]" } else { "" }
+        );
+        let details = self
+            .state
+            .selected()
+            .and_then(|i| visible.get(i))
+            .map(|entry| detail_lines(entry))
+            .unwrap_or_default();
+
+        let list = List::new(items)
+            .block(Block::default().title(title).borders(Borders::ALL))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        frame.render_stateful_widget(list, chunks[0], &mut self.state);
+
+        let details = Paragraph::new(details.join("\n"))
+            .block(Block::default().title("Details").borders(Borders::ALL));
+        frame.render_widget(details, chunks[1]);
+    }
+
+    /// Returns true once the user wants to leave
+    pub fn handle_input(&mut self) -> Result<bool> {
+        if let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            match key.code {
+                KeyCode::Esc => return Ok(true),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(true);
+                }
+                KeyCode::Down => self.next(),
+                KeyCode::Up => self.previous(),
+                KeyCode::Tab => {
+                    self.failed_only = !self.failed_only;
+                    self.clamp_selection();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                    self.clamp_selection();
+                }
+                KeyCode::Char(c) => {
+                    self.filter.push(c);
+                    self.clamp_selection();
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn detail_lines(entry: &HistoryEntry) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "{} on {} by {}, took {}",
+            entry.operation_label(),
+            entry.hostname,
+            entry.user.as_deref().unwrap_or("unknown user"),
+            entry.duration_label()
+        ),
+        format!(
+            "Outcome:    {}{}",
+            entry.outcome,
+            entry
+                .failure_reason
+                .as_deref()
+                .map(|reason| format!(": {}", reason))
+                .unwrap_or_default()
+        ),
+        format!(
+            "Git:        {} -> {}",
+            entry.git_rev_before.as_deref().unwrap_or("-"),
+            entry.git_rev_after.as_deref().unwrap_or("-")
+        ),
+        format!(
+            "Generation: {}",
+            entry
+                .generation
+                .map(|g| g.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        ),
+    ];
+    if let Some(run_dir) = &entry.run_dir {
+        lines.push(format!("Logs:       {}", run_dir.display()));
+    }
+    lines
+}
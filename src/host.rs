@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+/// A deployable NixOS machine as shown in the server selector.
+///
+/// Connection settings that are `None` fall back to the defaults used when
+/// connecting (the host name as address, port 22, user `root`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    pub name: String,
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Keys tried before the default ones in `~/.ssh`
+    pub identity_files: Vec<PathBuf>,
+    /// Jump hosts in ProxyJump syntax, from the inventory or SSH config
+    pub proxy_jump: Option<String>,
+    /// Seconds between keepalive messages on an idle connection
+    pub server_alive_interval: Option<u64>,
+    pub flake_attr: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl Host {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            address: None,
+            port: None,
+            user: None,
+            identity_files: Vec::new(),
+            proxy_jump: None,
+            server_alive_interval: None,
+            flake_attr: None,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn with_address(mut self, address: impl Into<String>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Selector entry in the `hostname:address` form
+    pub fn label(&self) -> String {
+        format!("{}:{}", self.name, self.ssh_address())
+    }
+
+    pub fn ssh_address(&self) -> &str {
+        self.address.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn ssh_port(&self) -> u16 {
+        self.port.unwrap_or(22)
+    }
+
+    pub fn ssh_user(&self) -> &str {
+        self.user.as_deref().unwrap_or("root")
+    }
+
+    /// Attribute under `nixosConfigurations` to build for this host
+    pub fn flake_attribute(&self) -> &str {
+        self.flake_attr.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Merge what another discovery source knows about the same host
+    ///
+    /// Values set on `other` win, tags are combined.
+    pub fn merge(&mut self, other: Host) {
+        if other.address.is_some() {
+            self.address = other.address;
+        }
+        if other.port.is_some() {
+            self.port = other.port;
+        }
+        if other.user.is_some() {
+            self.user = other.user;
+        }
+        for identity_file in other.identity_files {
+            if !self.identity_files.contains(&identity_file) {
+                self.identity_files.push(identity_file);
+            }
+        }
+        if other.proxy_jump.is_some() {
+            self.proxy_jump = other.proxy_jump;
+        }
+        if other.server_alive_interval.is_some() {
+            self.server_alive_interval = other.server_alive_interval;
+        }
+        if other.flake_attr.is_some() {
+            self.flake_attr = other.flake_attr;
+        }
+        for tag in other.tags {
+            if !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::discovery::Discovery;
+use crate::health::HealthSettings;
+use crate::host::Host;
+use crate::paths::{config_dir, expand_home};
+
+/// Declarative list of hosts, loaded from a TOML or JSON file
+///
+/// ```toml
+/// [[hosts]]
+/// name = "db-01"
+/// address = "10.20.0.11"
+/// port = 2222
+/// user = "deploy"
+/// flake = "db01"
+/// tags = ["db", "datacenter"]
+/// jump = "admin@bastion.example.com:2222"
+/// identity_files = ["~/.ssh/deploy_ed25519"]
+///
+/// [discovery]
+/// include = ["nix*", "/^web-\\d+$/"]
+/// exclude = ["nix-test*"]
+///
+/// [flake]
+/// template = "{name}"
+/// strip_prefix = "nix"
+/// attributes = { "web-01" = "web01" }
+///
+/// [jump]
+/// tags = { datacenter = "bastion" }
+///
+/// [[health.checks]]
+/// type = "unit"
+/// unit = "postgresql.service"
+/// tags = ["db"]
+/// ```
+///
+/// See [`HealthSettings`] for the health check options.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Inventory {
+    #[serde(default)]
+    pub hosts: Vec<InventoryHost>,
+    #[serde(default)]
+    pub discovery: DiscoverySettings,
+    #[serde(default)]
+    pub flake: FlakeSettings,
+    #[serde(default)]
+    pub health: HealthSettings,
+    #[serde(default)]
+    pub jump: JumpSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InventoryHost {
+    pub name: String,
+    /// IP or DNS name; defaults to the address from other sources, then `name`
+    pub address: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Attribute under `nixosConfigurations` to build
+    pub flake: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Jump hosts to connect through, in ProxyJump syntax
+    /// (`[user@]host[:port]`, comma separated for a chain)
+    pub jump: Option<String>,
+    /// Private keys tried before those from `~/.ssh/config` and the defaults
+    #[serde(default)]
+    pub identity_files: Vec<String>,
+}
+
+/// Host name patterns applied to every discovery backend
+///
+/// Globs (`*`, `?`) or regexes wrapped in slashes. Without include patterns,
+/// Tailscale peers are limited to names starting with `nix`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DiscoverySettings {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// How host names map to `nixosConfigurations` attributes
+///
+/// An explicit `flake` on a host wins over `attributes`, which wins over the
+/// template. The template understands `{hostname}` (the full host name) and
+/// `{name}` (the host name without `strip_prefix`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FlakeSettings {
+    pub template: String,
+    pub strip_prefix: String,
+    pub attributes: HashMap<String, String>,
+}
+
+impl Default for FlakeSettings {
+    fn default() -> Self {
+        Self {
+            template: "{name}".to_string(),
+            strip_prefix: "nix".to_string(),
+            attributes: HashMap::new(),
+        }
+    }
+}
+
+impl FlakeSettings {
+    pub fn attribute_for(&self, hostname: &str) -> String {
+        if let Some(attr) = self.attributes.get(hostname) {
+            return attr.clone();
+        }
+
+        let name = hostname
+            .strip_prefix(self.strip_prefix.as_str())
+            .unwrap_or(hostname);
+        self.template
+            .replace("{hostname}", hostname)
+            .replace("{name}", name)
+    }
+
+    /// Fill in the flake attribute of hosts that don't have an explicit one
+    pub fn apply(&self, hosts: &mut [Host]) {
+        for host in hosts {
+            if host.flake_attr.is_none() {
+                host.flake_attr = Some(self.attribute_for(&host.name));
+            }
+        }
+    }
+}
+
+/// Jump hosts for all hosts with a tag
+///
+/// A `jump` set on a host wins, then the first of the host's tags listed
+/// here, then `ProxyJump` from `~/.ssh/config`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct JumpSettings {
+    pub tags: HashMap<String, String>,
+}
+
+impl JumpSettings {
+    /// Fill in the jump hosts of hosts that don't have one
+    pub fn apply(&self, hosts: &mut [Host]) {
+        for host in hosts {
+            if host.proxy_jump.is_none() {
+                host.proxy_jump = host.tags.iter().find_map(|tag| self.tags.get(tag)).cloned();
+            }
+        }
+    }
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read inventory file {}", path.display()))?;
+
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+
+        if is_json {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse inventory JSON {}", path.display()))
+        } else {
+            toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse inventory TOML {}", path.display()))
+        }
+    }
+
+    /// `$XDG_CONFIG_HOME/nix-deploy/inventory.toml`, if that file exists
+    pub fn default_path() -> Option<PathBuf> {
+        let path = config_dir().ok()?.join("inventory.toml");
+        path.exists().then_some(path)
+    }
+}
+
+impl Discovery for Inventory {
+    fn name(&self) -> &'static str {
+        "inventory"
+    }
+
+    fn discover(&self) -> Result<Vec<Host>> {
+        self.hosts
+            .iter()
+            .map(|entry| {
+                Ok(Host {
+                    name: entry.name.clone(),
+                    address: entry.address.clone(),
+                    port: entry.port,
+                    user: entry.user.clone(),
+                    flake_attr: entry.flake.clone(),
+                    tags: entry.tags.clone(),
+                    proxy_jump: entry.jump.clone(),
+                    identity_files: entry
+                        .identity_files
+                        .iter()
+                        .map(|path| expand_home(path))
+                        .collect::<Result<_>>()?,
+                    ..Host::new(entry.name.clone())
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attribute_for_prefers_explicit_mapping_then_template() {
+        let settings = FlakeSettings {
+            template: "{name}-{hostname}".to_string(),
+            strip_prefix: "nix".to_string(),
+            attributes: HashMap::from([("nixweb".to_string(), "web01".to_string())]),
+        };
+
+        assert_eq!(settings.attribute_for("nixweb"), "web01");
+        assert_eq!(settings.attribute_for("nixdb"), "db-nixdb");
+        assert_eq!(settings.attribute_for("db"), "db-db");
+    }
+
+    #[test]
+    fn default_settings_strip_nix_prefix() {
+        let settings = FlakeSettings::default();
+        assert_eq!(settings.attribute_for("nixweb"), "web");
+        assert_eq!(settings.attribute_for("web"), "web");
+    }
+
+    #[test]
+    fn apply_keeps_explicit_flake_attribute() {
+        let mut explicit = Host::new("nixweb");
+        explicit.flake_attr = Some("custom".to_string());
+        let mut hosts = [explicit, Host::new("nixdb")];
+
+        FlakeSettings::default().apply(&mut hosts);
+
+        assert_eq!(hosts[0].flake_attribute(), "custom");
+        assert_eq!(hosts[1].flake_attribute(), "db");
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::Result;
+use ssh2::{Channel, ErrorCode, Session};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::host::Host;
+use crate::progress::{ProgressUpdate, UpdatePhase};
+use crate::ssh_config::SshConfig;
+use crate::ssh_executor::Keepalive;
+use crate::updater::{ConnectOptions, connect_session};
+
+/// libssh2's "would block" error code
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// Longest pause of an idle tunnel between looking for data
+///
+/// The jump session is shared, so its socket can't be waited on: another
+/// tunnel's thread may already have read the data meant for this one.
+const MAX_IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// Sessions to jump hosts, shared by every target behind the same chain
+///
+/// Each chain prefix (`bastion`, `bastion,inner`) gets one session, connected
+/// on first use. The sessions are non-blocking so that the tunnels of several
+/// targets can use them at the same time.
+#[derive(Default)]
+pub struct JumpPool {
+    sessions: Mutex<HashMap<String, Arc<Mutex<Option<Session>>>>>,
+}
+
+impl std::fmt::Debug for JumpPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let chains: Vec<String> = self
+            .sessions
+            .lock()
+            .map(|sessions| sessions.keys().cloned().collect())
+            .unwrap_or_default();
+        f.debug_struct("JumpPool").field("chains", &chains).finish()
+    }
+}
+
+/// Jump host from a `[user@]host[:port]` entry of a ProxyJump list
+///
+/// Settings missing from the entry come from the SSH config.
+fn jump_host(spec: &str, previous: &[&str], ssh_config: &SshConfig) -> Host {
+    let (user, rest) = match spec.rsplit_once('@') {
+        Some((user, rest)) => (Some(user.to_string()), rest),
+        None => (None, spec),
+    };
+    let (name, port) = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        // [address]:port, for IPv6 addresses
+        Some((name, tail)) => (name, tail.strip_prefix(':').and_then(|p| p.parse().ok())),
+        None => match rest.rsplit_once(':') {
+            Some((name, port)) if !name.contains(':') => (name, port.parse().ok()),
+            _ => (rest, None),
+        },
+    };
+
+    let mut host = Host::new(name);
+    host.user = user;
+    host.port = port;
+    ssh_config.apply(std::slice::from_mut(&mut host));
+    // Only the chain given for the target counts, not the jump host's own ProxyJump
+    host.proxy_jump = (!previous.is_empty()).then(|| previous.join(","));
+    host
+}
+
+impl JumpPool {
+    /// Session to the last host of `chain`, connecting it first if needed
+    fn session(&self, chain: &[&str], connect: &ConnectOptions) -> Result<Result<Session, String>> {
+        let key = chain.join(",");
+        let slot = self
+            .sessions
+            .lock()
+            .expect("jump pool poisoned")
+            .entry(key)
+            .or_default()
+            .clone();
+
+        // Holding the slot makes other targets wait for this connection
+        // instead of opening their own
+        let mut slot = slot.lock().expect("jump session poisoned");
+        if let Some(sess) = slot.as_ref() {
+            return Ok(Ok(sess.clone()));
+        }
+
+        let (last, previous) = chain.split_last().expect("jump chain is not empty");
+        let host = jump_host(last, previous, &connect.ssh_config);
+
+        // The jump host isn't part of the progress display
+        let (quiet_tx, _) = mpsc::channel(1);
+        let sess = match connect_session(&host, connect, Duration::from_secs(30), &quiet_tx) {
+            Ok(Ok(sess)) => sess,
+            Ok(Err(error)) => return Ok(Err(format!("Jump host {}: {}", last, error))),
+            Err(e) => return Ok(Err(format!("Jump host {}: {}", last, e))),
+        };
+        sess.set_blocking(false);
+
+        *slot = Some(sess.clone());
+        Ok(Ok(sess))
+    }
+
+    /// Open a connection to `address:port` through the jump hosts in `chain`
+    ///
+    /// The returned socket is forwarded to a `direct-tcpip` channel of the
+    /// last jump host by a background thread. Returns the failure reason if a
+    /// jump host could not be reached; it has already been sent to the TUI.
+    pub fn tunnel(
+        &self,
+        chain: &str,
+        hostname: &str,
+        address: &str,
+        port: u16,
+        connect: &ConnectOptions,
+        progress_tx: &mpsc::Sender<ProgressUpdate>,
+    ) -> Result<Result<UnixStream, String>> {
+        let hops: Vec<&str> = chain
+            .split(',')
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .collect();
+        if hops.is_empty() {
+            anyhow::bail!("Empty jump host list for {}", hostname);
+        }
+
+        let jump_sess = match self.session(&hops, connect)? {
+            Ok(sess) => sess,
+            Err(error) => {
+                let _ = progress_tx.try_send(ProgressUpdate {
+                    hostname: hostname.to_string(),
+                    phase: UpdatePhase::Failed {
+                        reason: "Jump host unreachable".to_string(),
+                    },
+                    output_line: Some(error.clone()),
+                });
+                return Ok(Err(error));
+            }
+        };
+
+        let channel = loop {
+            match jump_sess.channel_direct_tcpip(address, port, None) {
+                Ok(channel) => break channel,
+                Err(e) if e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => {
+                    // The jump session may have died, reconnect for the next target
+                    self.sessions
+                        .lock()
+                        .expect("jump pool poisoned")
+                        .remove(&hops.join(","));
+
+                    let error = format!(
+                        "Jump host {} could not open a connection to {}:{}: {}",
+                        hops[hops.len() - 1],
+                        address,
+                        port,
+                        e
+                    );
+                    let _ = progress_tx.try_send(ProgressUpdate {
+                        hostname: hostname.to_string(),
+                        phase: UpdatePhase::Failed {
+                            reason: "Jump host unreachable".to_string(),
+                        },
+                        output_line: Some(error.clone()),
+                    });
+                    return Ok(Err(error));
+                }
+            }
+        };
+
+        let (local, remote) = UnixStream::pair()?;
+        let sess = jump_sess.clone();
+        std::thread::spawn(move || forward(&sess, channel, remote));
+        Ok(Ok(local))
+    }
+}
+
+/// Copy data between the tunnel channel and the local end of the socket pair
+/// until either side closes
+fn forward(sess: &Session, mut channel: Channel, mut socket: UnixStream) {
+    if socket.set_nonblocking(true).is_err() {
+        return;
+    }
+
+    let mut buffer = [0u8; 32 * 1024];
+    let mut keepalive = Keepalive::new();
+    let mut idle_wait = Duration::ZERO;
+    loop {
+        let mut idle = true;
+
+        match channel.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                idle = false;
+                if write_all_retrying(&mut socket, &buffer[..n]).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+
+        match socket.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                idle = false;
+                if write_all_retrying(&mut channel, &buffer[..n]).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => break,
+        }
+
+        if idle {
+            // The jump session carries every tunnel through it, so it is kept
+            // alive even while all of them wait on long builds
+            let until_keepalive = keepalive.tick(sess);
+            // Back off while the tunnel stays idle, e.g. during a long build
+            idle_wait = (idle_wait * 2).clamp(Duration::from_millis(1), MAX_IDLE_WAIT);
+            std::thread::sleep(idle_wait.min(until_keepalive));
+        } else {
+            idle_wait = Duration::ZERO;
+        }
+    }
+
+    // The session is non-blocking, so closing may have to be retried
+    while let Err(e) = channel.close() {
+        if e.code() != ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// `write_all` for non-blocking writers
+fn write_all_retrying(writer: &mut impl Write, mut data: &[u8]) -> std::io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => data = &data[n..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use clap::ValueEnum;
+use ssh2::{CheckResult, HashType, KnownHostFileKind, MethodType, Session};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::paths::{config_dir, home_dir};
+
+/// What to do with the host key a server presents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum HostKeyChecking {
+    /// Only connect to hosts whose key is already known
+    Strict,
+    /// Remember the keys of new hosts, refuse changed keys
+    #[default]
+    AcceptNew,
+    /// Don't verify host keys
+    Off,
+}
+
+/// `~/.ssh/known_hosts`, only ever read
+fn user_known_hosts() -> Result<PathBuf> {
+    Ok(home_dir()?.join(".ssh/known_hosts"))
+}
+
+/// `$XDG_CONFIG_HOME/nix-deploy/known_hosts`, where newly accepted keys go
+fn own_known_hosts() -> Result<PathBuf> {
+    Ok(config_dir()?.join("known_hosts"))
+}
+
+/// Entries of both known_hosts files
+///
+/// Lines libssh2 can't parse (markers like @cert-authority, unsupported key
+/// types) are skipped instead of failing the whole file.
+fn known_host_lines() -> Vec<String> {
+    [user_known_hosts(), own_known_hosts()]
+        .into_iter()
+        .flatten()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|contents| {
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('@'))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Host name as written to known_hosts, `[address]:port` for other ports than 22
+fn entry_name(address: &str, port: u16) -> String {
+    if port == 22 {
+        address.to_string()
+    } else {
+        format!("[{}]:{}", address, port)
+    }
+}
+
+/// OpenSSH style fingerprint of the server's host key
+fn fingerprint(sess: &Session) -> String {
+    sess.host_key_hash(HashType::Sha256)
+        .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+        .unwrap_or_else(|| "unknown fingerprint".to_string())
+}
+
+/// Host key algorithms libssh2 should prefer for a host
+///
+/// Call before the handshake. The key types already recorded for the host come
+/// first, so a server with several keys presents the one we know instead of
+/// looking like it changed its key.
+pub fn prefer_known_key_types(sess: &Session, address: &str, port: u16) -> Result<()> {
+    let mut known_types: Vec<&str> = Vec::new();
+    let lines = known_host_lines();
+    for line in &lines {
+        // Parsing the line on its own also resolves hashed host names
+        let mut single = sess.known_hosts()?;
+        if single.read_str(line, KnownHostFileKind::OpenSSH).is_err() {
+            continue;
+        }
+        // Any key will do, only whether the host name matches is of interest
+        if matches!(
+            single.check_port(address, port, b"-"),
+            CheckResult::NotFound
+        ) {
+            continue;
+        }
+        if let Some(key_type) = line.split_whitespace().nth(1)
+            && !known_types.contains(&key_type)
+        {
+            known_types.push(key_type);
+        }
+    }
+
+    if known_types.is_empty() {
+        return Ok(());
+    }
+
+    let supported = sess.supported_algs(MethodType::HostKey)?;
+    let mut preferred: Vec<&str> = Vec::new();
+    for key_type in known_types {
+        // RSA keys are recorded as ssh-rsa but signed with the SHA-2 variants
+        let algorithms: &[&str] = match key_type {
+            "ssh-rsa" => &["rsa-sha2-512", "rsa-sha2-256", "ssh-rsa"],
+            other => &[other],
+        };
+        for &algorithm in algorithms {
+            if supported.contains(&algorithm) && !preferred.contains(&algorithm) {
+                preferred.push(algorithm);
+            }
+        }
+    }
+    for &algorithm in &supported {
+        if !preferred.contains(&algorithm) {
+            preferred.push(algorithm);
+        }
+    }
+
+    sess.method_pref(MethodType::HostKey, &preferred.join(","))?;
+    Ok(())
+}
+
+/// Check the key presented during the handshake against known_hosts
+///
+/// Returns the failure reason and a detailed message if the key is not
+/// accepted. With accept-new, keys of unknown hosts are added to the
+/// nix-deploy known_hosts file.
+pub fn verify_host_key(
+    sess: &Session,
+    address: &str,
+    port: u16,
+    mode: HostKeyChecking,
+) -> Result<std::result::Result<(), (String, String)>> {
+    if mode == HostKeyChecking::Off {
+        return Ok(Ok(()));
+    }
+
+    let (key, key_type) = sess
+        .host_key()
+        .ok_or_else(|| anyhow::anyhow!("Server did not present a host key"))?;
+
+    let mut known_hosts = sess.known_hosts()?;
+    for line in known_host_lines() {
+        let _ = known_hosts.read_str(&line, KnownHostFileKind::OpenSSH);
+    }
+
+    let name = entry_name(address, port);
+    match known_hosts.check_port(address, port, key) {
+        CheckResult::Match => Ok(Ok(())),
+        CheckResult::Mismatch => Ok(Err((
+            "Host key mismatch".to_string(),
+            format!(
+                "Host key for {} ({}) does not match known_hosts; refusing to connect. \
+                 If the key changed on purpose, remove the old entry with ssh-keygen -R '{}'",
+                name,
+                fingerprint(sess),
+                name
+            ),
+        ))),
+        CheckResult::NotFound if mode == HostKeyChecking::Strict => Ok(Err((
+            "Unknown host key".to_string(),
+            format!(
+                "Host key for {} ({}) is not in known_hosts and host key checking is strict",
+                name,
+                fingerprint(sess)
+            ),
+        ))),
+        CheckResult::NotFound => {
+            let mut new_entry = sess.known_hosts()?;
+            new_entry.add(&name, key, "added by nix-deploy", key_type.into())?;
+            let entry = new_entry
+                .hosts()?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Failed to record host key for {}", name))?;
+            let line = new_entry.write_string(&entry, KnownHostFileKind::OpenSSH)?;
+
+            let path = own_known_hosts()?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            file.write_all(line.trim_end().as_bytes())?;
+            file.write_all(b"\n")?;
+            Ok(Ok(()))
+        }
+        CheckResult::Failure => anyhow::bail!("Failed to check the host key of {}", name),
+    }
+}
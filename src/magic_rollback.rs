@@ -0,0 +1,177 @@
+use anyhow::Result;
+use ssh2::Session;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::host::Host;
+use crate::progress::{ProgressUpdate, UpdatePhase};
+use crate::ssh_executor::{Sudo, execute_privileged};
+use crate::updater::{ConnectOptions, connect_session};
+
+/// Remote timer that switches back to the previous generation unless the
+/// deployment is confirmed over a fresh SSH connection
+///
+/// The watchdog runs as a transient systemd unit so it survives sshd and
+/// network restarts. The deploy's activation command, wrapped with
+/// [`Watchdog::track`], records its PID; the countdown starts once that
+/// process has exited and the system profile has moved past
+/// `previous_generation`.
+pub struct Watchdog {
+    unit: String,
+    pid_file: String,
+    previous_generation: u64,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    pub fn arm(
+        sess: &Session,
+        hostname: &str,
+        forward_agent: bool,
+        sudo: &Sudo,
+        previous_generation: u64,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let unit = format!("nix-deploy-rollback-{}", stamp);
+        let script_path = format!("/run/nix-deploy/{}.sh", unit);
+        let pid_file = format!("/run/nix-deploy/{}.pid", unit);
+
+        let script = format!(
+            r#"profile=/nix/var/nix/profiles/system
+while [ ! -s {pid_file} ]; do sleep 1; done
+pid=$(cat {pid_file})
+while kill -0 "$pid" 2> /dev/null; do sleep 1; done
+if [ "$(readlink $profile)" = "system-{generation}-link" ]; then
+  echo "Activation did not create a new generation, nothing to roll back"
+  exit 0
+fi
+sleep {timeout}
+echo "No confirmation from nix-deploy, rolling back to generation {generation}"
+nix-env -p $profile --switch-generation {generation} && $profile/bin/switch-to-configuration switch
+"#,
+            pid_file = pid_file,
+            generation = previous_generation,
+            timeout = timeout.as_secs()
+        );
+
+        let arm_cmd = format!(
+            "mkdir -p /run/nix-deploy && cat > {path} <<'NIX_DEPLOY_EOF'\n{script}NIX_DEPLOY_EOF\n\
+             systemd-run --unit {unit} --description 'nix-deploy magic rollback for {hostname}' \
+             --collect --property RuntimeMaxSec=6h sh {path}",
+            path = script_path,
+            script = script,
+            unit = unit,
+            hostname = hostname,
+        );
+
+        let (output, exit_status) = execute_privileged(sess, &arm_cmd, forward_agent, sudo)?;
+        if exit_status != 0 {
+            anyhow::bail!("Failed to start rollback watchdog: {}", output.trim());
+        }
+
+        Ok(Self {
+            unit,
+            pid_file,
+            previous_generation,
+            timeout,
+        })
+    }
+
+    /// Wrap the activation command so the watchdog waits for this process
+    ///
+    /// The shell records its own PID before running `command`; it exits when
+    /// the activation finishes or the SSH connection drops.
+    pub fn track(&self, command: &str) -> String {
+        format!("echo $$ > {} && {}", self.pid_file, command)
+    }
+
+    /// Stop the watchdog after a failed deployment; best effort since the
+    /// session may already be gone
+    pub fn disarm(&self, sess: &Session, forward_agent: bool, sudo: &Sudo) {
+        let _ = execute_privileged(
+            sess,
+            &format!("systemctl stop {}", self.unit),
+            forward_agent,
+            sudo,
+        );
+    }
+
+    /// Reconnect with a new session and stop the watchdog
+    ///
+    /// Returns the new session, or the failure reason if the host could not be
+    /// reached in time or had already rolled back.
+    pub fn confirm(
+        &self,
+        host: &Host,
+        connect: &ConnectOptions,
+        forward_agent: bool,
+        sudo: &Sudo,
+        progress_tx: &mpsc::Sender<ProgressUpdate>,
+    ) -> Result<std::result::Result<Session, String>> {
+        let hostname = host.name.as_str();
+        // Leave the watchdog some slack so we never confirm during its rollback
+        let deadline = Instant::now() + self.timeout * 3 / 4;
+
+        // Connection attempts report Connecting/Failed phases; keep those out
+        // of the TUI while retrying
+        let (quiet_tx, _) = mpsc::channel(1);
+
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: hostname.to_string(),
+            phase: UpdatePhase::Confirming,
+            output_line: Some(format!(
+                "Reconnecting to confirm (rollback to generation {} in {}s otherwise)...",
+                self.previous_generation,
+                self.timeout.as_secs()
+            )),
+        });
+
+        let mut attempt = 1;
+        let sess = loop {
+            let error = match connect_session(host, connect, Duration::from_secs(10), &quiet_tx) {
+                Ok(Ok(sess)) => break sess,
+                Ok(Err(error)) => error,
+                Err(e) => e.to_string(),
+            };
+
+            if Instant::now() >= deadline {
+                return Ok(Err(format!(
+                    "Could not reconnect after activation ({}); the host rolls back to generation {}",
+                    error, self.previous_generation
+                )));
+            }
+
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::Confirming,
+                output_line: Some(format!("Reconnect attempt {} failed: {}", attempt, error)),
+            });
+            attempt += 1;
+            std::thread::sleep(Duration::from_secs(5));
+        };
+
+        let confirm_cmd = format!(
+            "systemctl is-active --quiet {0} && systemctl stop {0}",
+            self.unit
+        );
+        let (_, exit_status) = execute_privileged(&sess, &confirm_cmd, forward_agent, sudo)?;
+        if exit_status != 0 {
+            return Ok(Err(format!(
+                "Watchdog was no longer running; the host may have rolled back to generation {}",
+                self.previous_generation
+            )));
+        }
+
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: hostname.to_string(),
+            phase: UpdatePhase::Confirming,
+            output_line: Some("✓ Reconnected and confirmed, rollback cancelled".to_string()),
+        });
+
+        Ok(Ok(sess))
+    }
+}
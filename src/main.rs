@@ -1,69 +1,146 @@
+mod action;
+mod closure;
+mod discovery;
+mod health;
+mod history;
+mod history_tui;
+mod host;
+mod identity;
+mod inventory;
+mod jump;
+mod known_hosts;
+mod magic_rollback;
+mod nix_log;
+mod paths;
+mod plan;
 mod progress;
+mod progress_plain;
 mod progress_tui;
+mod reboot;
+mod rebuild_error;
+mod replay;
+mod report;
+mod rollback;
+mod run_log;
+mod ssh_config;
 mod ssh_executor;
+mod status;
+mod strategy;
 mod updater;
 
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{Result, bail};
+use clap::{Parser, Subcommand, ValueEnum};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
 use futures::future::join_all;
 use ratatui::{
     prelude::*,
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
 };
-use serde::Deserialize;
-use ssh2::Session;
-use std::{collections::HashMap, net::TcpStream, process::Command};
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-use progress::{create_progress_map, progress_monitor_task};
+use action::RebuildAction;
+use closure::LocalBuild;
+use discovery::{
+    Backend, Discovery, FlakeDiscovery, HostFilter, HostPattern, SshConfigDiscovery,
+    TailscaleDiscovery, discover_hosts,
+};
+use health::HealthSettings;
+use history::HistoryEntry;
+use history_tui::HistoryView;
+use host::Host;
+use inventory::Inventory;
+use known_hosts::HostKeyChecking;
+use progress::{ProgressUpdate, create_progress_map, progress_monitor_task};
+use progress_plain::plain_progress_task;
 use progress_tui::ProgressTui;
-use ssh_executor::execute_command_on_channel;
-use updater::update_server_with_progress;
-
-#[derive(Debug, Deserialize)]
-struct TailscaleStatus {
-    #[serde(rename = "Self")]
-    self_info: TailscaleSelf,
-    #[serde(rename = "Peer")]
-    peers: HashMap<String, TailscalePeer>,
-}
-
-#[derive(Debug, Deserialize)]
-struct TailscaleSelf {
-    #[serde(rename = "HostName")]
-    host_name: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct TailscalePeer {
-    #[serde(rename = "HostName")]
-    host_name: String,
-    #[serde(rename = "TailscaleIPs")]
-    ips: Vec<String>,
-    #[serde(rename = "Online")]
-    online: bool,
-}
+use replay::EventRecorder;
+use report::{DeploymentReport, ReportFormat};
+use run_log::{RunLog, RunParameters};
+use ssh_config::SshConfig;
+use status::{HostStatus, query_statuses};
+use strategy::Strategy;
+use updater::{ConnectOptions, Operation, UpdateOptions, UpdateResult};
 
 #[derive(Parser)]
 #[command(version, about = "Update NixOS servers", long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
     /// Use 'nixos-rebuild boot' instead of 'nixos-rebuild switch'
-    #[arg(short, long)]
+    ///
+    /// Shorthand for --action boot; takes precedence over --action.
+    #[arg(short, long, global = true)]
     boot: bool,
 
+    /// What to do with the new configuration
+    ///
+    /// switch and boot add a system generation; test activates the configuration
+    /// without a boot entry, dry-activate lists the units that would be
+    /// stopped, restarted, started or reloaded, build only builds (with
+    /// --local-build: and copies the closure to the host) and dry-build shows
+    /// what would be built. Actions other than switch and boot build the
+    /// fetched upstream revision without pulling it into /etc/nixos.
+    #[arg(long, value_enum, default_value_t = RebuildAction::Switch)]
+    action: RebuildAction,
+
     /// Enable SSH agent forwarding (equivalent to ssh -A)
     ///
     /// WARNING: This allows the remote server to use your SSH agent to authenticate
     /// to other servers. Only use this if you trust the remote server and need it
     /// to access other systems using your credentials.
-    #[arg(long)]
+    #[arg(long, global = true)]
     forward_agent: bool,
 
+    /// SSH user for hosts without one in the inventory or ~/.ssh/config
+    /// (default: root)
+    ///
+    /// For any user other than root, privileged commands (git in /etc/nixos,
+    /// nixos-rebuild, activation, copying closures, reboots) run through sudo,
+    /// which must not ask for a password unless --ask-sudo-password is given.
+    #[arg(long, global = true, value_name = "USER")]
+    ssh_user: Option<String>,
+
+    /// SSH port for hosts without one in the inventory or ~/.ssh/config
+    /// (default: 22)
+    #[arg(long, global = true, value_name = "PORT")]
+    ssh_port: Option<u16>,
+
+    /// Private key to try first on every host (repeatable)
+    ///
+    /// Keys from the inventory and ~/.ssh/config come next, then the default
+    /// keys in ~/.ssh. The passphrases of encrypted keys the SSH agent doesn't
+    /// hold are asked for once the hosts are selected. A certificate next to a
+    /// key (<key>-cert.pub) is sent along with it.
+    #[arg(long = "identity-file", global = true, value_name = "PATH")]
+    identity_files: Vec<String>,
+
+    /// How to verify the host keys of the servers
+    ///
+    /// Keys are checked against ~/.ssh/known_hosts and the nix-deploy
+    /// known_hosts file in $XDG_CONFIG_HOME/nix-deploy. strict only connects to
+    /// hosts with a known key, accept-new records the keys of new hosts in the
+    /// nix-deploy file, off skips the check. A changed key always fails the
+    /// host with "Host key mismatch" unless checking is off.
+    #[arg(long, global = true, value_enum, default_value_t = HostKeyChecking::AcceptNew)]
+    host_key_checking: HostKeyChecking,
+
+    /// Prompt once for the sudo password of non-root SSH users
+    ///
+    /// The password is sent to sudo on every host that isn't logged into as
+    /// root. Only use this if sudo asks for a password on all of those hosts;
+    /// with passwordless sudo the password would end up on the command's input.
+    #[arg(long, global = true)]
+    ask_sudo_password: bool,
+
     /// Command to run on each server in relation to the update process
     ///
     /// The command will be executed via SSH. By default, it runs BEFORE the update
@@ -75,6 +152,8 @@ struct Args {
     /// When running after: The command only executes if the update succeeds. If it
     /// fails, it will be marked as a failure but won't affect the update itself.
     ///
+    /// The command runs as the SSH user; prefix it with sudo if it needs root.
+    ///
     /// Example: --command "systemctl stop myapp" (runs before by default)
     /// Example: --command "systemctl restart myapp" --after (runs after update)
     #[arg(long)]
@@ -89,16 +168,325 @@ struct Args {
     /// Note: This flag has no effect if --command is not specified.
     #[arg(long, requires = "command")]
     after: bool,
+
+    /// Host discovery backends to query, comma separated
+    ///
+    /// Hosts with the same name are merged; backends listed later override the
+    /// values (address, port, user, flake attribute) of earlier ones and tags
+    /// are combined.
+    ///
+    /// Defaults to tailscale, followed by inventory if an inventory file is found.
+    #[arg(long, global = true, value_enum, value_delimiter = ',')]
+    discover: Vec<Backend>,
+
+    /// Skip Tailscale discovery, e.g. to only use hosts from the inventory file
+    ///
+    /// Removes tailscale from the default or --discover backends.
+    #[arg(long, global = true)]
+    no_tailscale: bool,
+
+    /// Inventory file (TOML or JSON) for the inventory backend
+    ///
+    /// Besides hosts, the file holds the [discovery] include/exclude patterns and
+    /// the [flake] host to attribute mapping.
+    ///
+    /// Defaults to $XDG_CONFIG_HOME/nix-deploy/inventory.toml if it exists.
+    #[arg(long, global = true, value_name = "PATH")]
+    inventory: Option<PathBuf>,
+
+    /// Local flake used by the flake backend and --local-build
+    #[arg(long, global = true, value_name = "FLAKE", default_value = ".")]
+    flake: String,
+
+    /// Build each host's system locally and push the closure over SSH
+    ///
+    /// Instead of running git pull and nixos-rebuild on every host, the
+    /// nixosConfigurations.<host>.config.system.build.toplevel of --flake is
+    /// built once on this machine, the store paths missing on the host are copied
+    /// over the existing SSH session, and the system profile is switched with
+    /// switch-to-configuration.
+    #[arg(long)]
+    local_build: bool,
+
+    /// Build on this remote nix store (e.g. "builder" or "ssh-ng://builder")
+    /// instead of locally; the result is copied back before pushing
+    #[arg(long, value_name = "STORE", requires = "local_build")]
+    build_host: Option<String>,
+
+    /// Roll back automatically if the new configuration breaks SSH access
+    ///
+    /// Before activation, a watchdog unit is started on the host. After
+    /// activation, nix-deploy must open a fresh SSH connection and confirm within
+    /// --confirm-timeout seconds, otherwise the watchdog switches back to the
+    /// previous generation. Only used with the switch action.
+    #[arg(long)]
+    magic_rollback: bool,
+
+    /// Seconds the watchdog waits for confirmation after activation
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 120,
+        requires = "magic_rollback"
+    )]
+    confirm_timeout: u64,
+
+    /// Don't run the [health] checks of the inventory file after activation
+    ///
+    /// Health checks (unit active, no failed units, HTTP endpoint, TCP port) run
+    /// over the same SSH session once the new configuration is active, and a
+    /// host only counts as updated if all of its checks pass. They only run for
+    /// switch, test and boot with --reboot since other actions leave the running
+    /// system alone.
+    #[arg(long)]
+    skip_health_checks: bool,
+
+    /// Reboot hosts after a boot deployment and wait for them to come back
+    ///
+    /// After `nixos-rebuild boot`, the host is rebooted, nix-deploy waits for SSH
+    /// to return and verifies that /run/current-system is the new generation.
+    /// Health checks then run against the rebooted system. Combine with
+    /// --parallel for rolling reboots.
+    #[arg(long)]
+    reboot: bool,
+
+    /// Seconds to wait for a rebooted host to accept SSH connections again
+    #[arg(long, value_name = "SECS", default_value_t = 600, requires = "reboot")]
+    reboot_timeout: u64,
+
+    /// Show the drift status of every host in the selector (see `status`)
+    #[arg(long)]
+    status: bool,
+
+    /// Preview the changes on each host before deploying
+    ///
+    /// The new system is built (on the host, from the upstream revision of
+    /// /etc/nixos, or locally with --local-build and copied over) and compared to
+    /// /run/current-system with `nix store diff-closures`. A confirmation screen
+    /// then lists added, removed and changed packages and the size change per
+    /// host; hosts without changes are deselected. Without a terminal, the plan
+    /// is printed and nothing is deployed.
+    #[arg(long)]
+    plan: bool,
+
+    /// Update at most N hosts at the same time (default: all at once)
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    parallel: Option<u64>,
+
+    /// Deploy hosts with these tags in waves, in the given order (comma separated)
+    ///
+    /// Each wave starts once the previous one has finished. Hosts without any of
+    /// the tags are deployed in a final wave.
+    ///
+    /// Example: --waves canary,db,web
+    #[arg(long, global = true, value_name = "TAG", value_delimiter = ',')]
+    waves: Vec<String>,
+
+    /// Update the first host on its own and continue only if it succeeds,
+    /// including its health checks
+    #[arg(long, global = true)]
+    canary: bool,
+
+    /// Skip the hosts that haven't started yet as soon as one host fails
+    #[arg(long, global = true)]
+    stop_on_failure: bool,
+
+    /// Only offer hosts whose name matches this pattern (repeatable)
+    ///
+    /// Patterns are globs (`web-*`) or regexes wrapped in slashes (`/^web-\d+$/`)
+    /// and are added to the [discovery] include list of the inventory file.
+    /// Without any include pattern, Tailscale peers are limited to names starting
+    /// with "nix".
+    #[arg(long, global = true, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Hide hosts whose name matches this pattern (repeatable)
+    #[arg(long, global = true, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Update these hosts without the interactive selector, comma separated
+    #[arg(long, global = true, value_name = "HOST", value_delimiter = ',')]
+    hosts: Vec<String>,
+
+    /// Update every discovered host without the interactive selector
+    #[arg(long, global = true, conflicts_with_all = ["hosts", "tag"])]
+    all: bool,
+
+    /// Update all hosts with this tag without the interactive selector (repeatable)
+    ///
+    /// Can be combined with --hosts; a host is selected if it matches either.
+    #[arg(long, global = true, value_name = "TAG")]
+    tag: Vec<String>,
+
+    /// Write a deployment report after the run (repeatable)
+    ///
+    /// FORMAT is json or junit. The report lists per host the phases reached with
+    /// their timings, the exit code of each command, the git revision before and
+    /// after pulling, the new system generation and the captured output.
+    ///
+    /// Example: --report junit results.xml --report json results.json
+    ///
+    /// Independently of this, every deployment is recorded in a directory under
+    /// $XDG_STATE_HOME/nix-deploy/runs with the run parameters, the JSON report
+    /// and a raw and an ANSI-stripped log per host.
+    #[arg(long, global = true, num_args = 2, value_names = ["FORMAT", "PATH"])]
+    report: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Switch the selected hosts back to an earlier system generation
+    ///
+    /// Hosts are selected the same way as for a deployment. Without
+    /// --generation, each host goes back to the generation before its current
+    /// one. With --boot, the generation is only made the boot default.
+    ///
+    /// Example: nix-deploy rollback --hosts web-01 --generation 41
+    Rollback {
+        /// Generation number to switch to
+        #[arg(long, value_name = "N")]
+        generation: Option<u64>,
+    },
+    /// Show how far each host has drifted without changing anything
+    ///
+    /// For every discovered host (or those picked with --hosts, --tag or --all):
+    /// the revision of /etc/nixos and whether it is behind origin or has local
+    /// changes, the current generation and its age, the NixOS version and
+    /// whether a reboot is pending because /run/booted-system differs from
+    /// /run/current-system.
+    Status,
+    /// List past deployments recorded on this machine
+    ///
+    /// Every run adds one entry per host to
+    /// $XDG_STATE_HOME/nix-deploy/history.jsonl: when it started and who started
+    /// it, the operation, the outcome and failure reason, the git revision
+    /// before and after, the new generation and how long it took. In a
+    /// terminal, the entries open in a view that filters by host name while
+    /// typing; otherwise they are printed as a table, newest first.
+    ///
+    /// Example: nix-deploy history 'web-*' --failed
+    History {
+        /// Only show hosts matching this pattern (glob or /regex/)
+        #[arg(value_name = "PATTERN")]
+        host: Option<String>,
+        /// Only show failed deployments
+        #[arg(long)]
+        failed: bool,
+        /// Show at most N entries
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Play a recorded run back in the progress view
+    ///
+    /// Shows the final phases and the complete output of every host as they
+    /// were during the run. RUN is a run directory or its name under
+    /// $XDG_STATE_HOME/nix-deploy/runs, by default the latest run. Without a
+    /// terminal, the output is printed line by line.
+    ///
+    /// Example: nix-deploy replay 2025-01-31T14-05-09Z --real-time
+    Replay {
+        #[arg(value_name = "RUN")]
+        run: Option<String>,
+        /// Play the updates back at the pace they were recorded
+        #[arg(long)]
+        real_time: bool,
+    },
+}
+
+impl Args {
+    fn is_headless(&self) -> bool {
+        self.all || !self.hosts.is_empty() || !self.tag.is_empty()
+    }
+
+    fn reports(&self) -> Result<Vec<(ReportFormat, PathBuf)>> {
+        self.report
+            .chunks(2)
+            .map(|pair| {
+                let format = ReportFormat::from_str(&pair[0], true)
+                    .map_err(|e| anyhow::anyhow!("Invalid report format '{}': {}", pair[0], e))?;
+                Ok((format, PathBuf::from(&pair[1])))
+            })
+            .collect()
+    }
+}
+
+/// Pick hosts from --hosts, --tag and --all instead of the selector
+fn select_hosts(args: &Args, nixos_servers: Vec<Host>) -> Result<Vec<Host>> {
+    if args.all {
+        return Ok(nixos_servers);
+    }
+
+    let unknown: Vec<&str> = args
+        .hosts
+        .iter()
+        .filter(|name| !nixos_servers.iter().any(|h| &h.name == *name))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        bail!("Unknown host(s): {}", unknown.join(", "));
+    }
+
+    Ok(nixos_servers
+        .into_iter()
+        .filter(|h| args.hosts.contains(&h.name) || h.tags.iter().any(|t| args.tag.contains(t)))
+        .collect())
+}
+
+fn discovery_backends(
+    args: &Args,
+    inventory: Option<&Inventory>,
+    include_configured: bool,
+) -> Result<Vec<Box<dyn Discovery>>> {
+    let mut selected = if args.discover.is_empty() {
+        let mut defaults = vec![Backend::Tailscale];
+        if inventory.is_some() {
+            defaults.push(Backend::Inventory);
+        }
+        defaults
+    } else {
+        args.discover.clone()
+    };
+    if args.no_tailscale {
+        selected.retain(|backend| *backend != Backend::Tailscale);
+    }
+
+    let mut backends: Vec<Box<dyn Discovery>> = Vec::new();
+    for backend in selected {
+        match backend {
+            Backend::Tailscale => {
+                let filter = if include_configured {
+                    HostFilter::default()
+                } else {
+                    HostFilter::new(&["nix*".to_string()], &[])?
+                };
+                backends.push(Box::new(TailscaleDiscovery { filter }));
+            }
+            Backend::Inventory => {
+                let Some(inventory) = inventory else {
+                    bail!("The inventory backend requires --inventory or a default inventory file");
+                };
+                backends.push(Box::new(inventory.clone()));
+            }
+            Backend::SshConfig => backends.push(Box::new(SshConfigDiscovery {
+                path: SshConfig::default_path()?,
+            })),
+            Backend::Flake => backends.push(Box::new(FlakeDiscovery {
+                flake: args.flake.clone(),
+            })),
+        }
+    }
+
+    Ok(backends)
 }
 
 struct ServerSelector {
-    servers: Vec<String>,
+    servers: Vec<Host>,
     selected: Vec<bool>,
     state: ListState,
 }
 
 impl ServerSelector {
-    fn new(servers: Vec<String>) -> Self {
+    fn new(servers: Vec<Host>) -> Self {
         let len = servers.len();
         let mut state = ListState::default();
         state.select(Some(0));
@@ -142,7 +530,7 @@ impl ServerSelector {
         self.selected = vec![!all_selected; self.servers.len()];
     }
 
-    fn get_selected_servers(&self) -> Vec<String> {
+    fn get_selected_servers(&self) -> Vec<Host> {
         self.servers
             .iter()
             .zip(self.selected.iter())
@@ -155,192 +543,192 @@ impl ServerSelector {
     }
 }
 
-fn get_nixos_servers() -> Result<Vec<String>> {
-    let output = Command::new("tailscale")
-        .arg("status")
-        .arg("--json")
-        .output()
-        .context("Failed to execute tailscale command")?;
-
-    let status: TailscaleStatus =
-        serde_json::from_slice(&output.stdout).context("Failed to parse tailscale status JSON")?;
-
-    let mut nixos_servers = Vec::new();
-    for (_, peer) in status.peers {
-        if peer.host_name.starts_with("nix") && !peer.ips.is_empty() && peer.online {
-            nixos_servers.push(format!("{}:{}", peer.host_name, peer.ips[0]));
-        }
-    }
-
-    nixos_servers.sort_by(|a, b| {
-        let a_host = a.split(':').next().unwrap_or("");
-        let b_host = b.split(':').next().unwrap_or("");
-        a_host.cmp(b_host)
-    });
+fn run_tui(
+    nixos_servers: Vec<Host>,
+    statuses: Option<&[Result<HostStatus, String>]>,
+) -> Result<Vec<Host>> {
+    enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
 
-    Ok(nixos_servers)
-}
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
 
-// Legacy update_server for backward compatibility (used in old code paths)
-fn update_server(
-    server_info: &str,
-    use_boot: bool,
-    forward_agent: bool,
-    command: Option<String>,
-    run_after: bool,
-) -> Result<(String, bool, String)> {
-    let parts: Vec<&str> = server_info.split(':').collect();
-    if parts.len() < 2 {
-        return Ok((
-            server_info.to_string(),
-            false,
-            "Invalid server info format".to_string(),
-        ));
-    }
+    let mut selector = ServerSelector::new(nixos_servers);
 
-    let hostname = parts[0];
-    let ip = parts[1];
+    let result = loop {
+        terminal.draw(|frame| {
+            let area = frame.area();
 
-    let flake_hostname = if hostname.starts_with("nix") {
-        &hostname[3..]
-    } else {
-        hostname
-    };
+            let items: Vec<ListItem> = selector
+                .servers
+                .iter()
+                .enumerate()
+                .map(|(i, server)| {
+                    let prefix = if selector.selected[i] { "[X] " } else { "[ ] " };
+                    let (status, color) = match statuses.and_then(|s| s.get(i)) {
+                        Some(Ok(status)) if status.has_drift() => {
+                            (format!("  {}", status.summary()), Color::Yellow)
+                        }
+                        Some(Ok(status)) => (format!("  {}", status.summary()), Color::Reset),
+                        Some(Err(e)) => (format!("  {}", e), Color::Red),
+                        None => (String::new(), Color::Reset),
+                    };
+                    ListItem::new(format!("{}{}{}", prefix, server.label(), status))
+                        .style(Style::default().fg(color))
+                })
+                .collect();
 
-    let tcp = TcpStream::connect(format!("{}:22", ip))?;
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
+            let list = List::new(items)
+                .block(
+                    Block::default()
+                        .title("NixOS Servers")
+                        .borders(Borders::ALL),
+                )
+                .highlight_style(Style::default().fg(Color::Yellow))
+                .highlight_symbol("> ");
 
-    sess.userauth_agent("root")?;
+            frame.render_stateful_widget(list, area, &mut selector.state);
 
-    let mut output = String::new();
-    let mut success = true;
+            let help_text = "\nPress Space to select, A to toggle all, Enter to confirm, Q to quit";
+            let help_paragraph =
+                Paragraph::new(help_text).block(Block::default().borders(Borders::NONE));
 
-    // Execute before-command if provided and run_after is false (default)
-    if !run_after {
-        if let Some(ref cmd) = command {
-            output.push_str(&format!("=== Running before-command ===\n"));
+            let help_area = Rect::new(area.x, area.bottom() - 2, area.width, 2);
 
-            let (buf, exit_status) = execute_command_on_channel(&sess, cmd, forward_agent)?;
-            output.push_str(&format!("$ {}\n{}\n", cmd, buf));
+            frame.render_widget(help_paragraph, help_area);
+        })?;
 
-            if exit_status != 0 {
-                success = false;
-                output.push_str(&format!(
-                    "Before-command failed with exit code: {}\n",
-                    exit_status
-                ));
-                return Ok((hostname.to_string(), success, output));
+        if let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            match key.code {
+                KeyCode::Char('q') => break Vec::new(), // Cancel operation
+                KeyCode::Char(' ') => selector.toggle_selected(),
+                KeyCode::Char('a') => selector.toggle_all(),
+                KeyCode::Down => selector.next(),
+                KeyCode::Up => selector.previous(),
+                KeyCode::Enter => break selector.get_selected_servers(),
+                _ => {}
             }
         }
-    }
+    };
 
-    let (git_check, _) = execute_command_on_channel(
-        &sess,
-        "test -d /etc/nixos/.git || echo 'No git repo found'",
-        forward_agent,
-    )?;
+    disable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
 
-    if git_check.contains("No git repo found") {
-        return Ok((
-            hostname.to_string(),
-            false,
-            "No git repository found in /etc/nixos".to_string(),
-        ));
-    }
+    Ok(result)
+}
 
-    let rebuild_mode = if use_boot { "boot" } else { "switch" };
-    let rebuild_cmd = format!(
-        "nixos-rebuild {} --flake \"/etc/nixos#{}\" --no-write-lock-file",
-        rebuild_mode, flake_hostname
+fn print_status_table(hosts: &[Host], statuses: &[Result<HostStatus, String>]) {
+    let name_width = hosts.iter().map(|h| h.name.len()).max().unwrap_or(4).max(4);
+    println!(
+        "{:<name_width$}  {:<28}  {:<18}  {:<24}  REBOOT",
+        "HOST", "GIT", "GENERATION", "NIXOS"
     );
 
-    for cmd in &["cd /etc/nixos && git pull --verbose", &rebuild_cmd] {
-        let (buf, exit_status) = execute_command_on_channel(&sess, cmd, forward_agent)?;
-        output.push_str(&format!("$ {}\n{}\n", cmd, buf));
-
-        if exit_status != 0 {
-            success = false;
-            output.push_str(&format!("Command failed with exit code: {}\n", exit_status));
-            break;
-        }
-    }
-
-    // Execute after-command if provided, run_after is true, and previous commands succeeded
-    if success && run_after {
-        if let Some(ref cmd) = command {
-            output.push_str(&format!("=== Running after-command ===\n"));
-            let (buf, exit_status) = execute_command_on_channel(&sess, cmd, forward_agent)?;
-            output.push_str(&format!("$ {}\n{}\n", cmd, buf));
-
-            if exit_status != 0 {
-                success = false;
-                output.push_str(&format!(
-                    "After-command failed with exit code: {}\n",
-                    exit_status
-                ));
-            }
+    for (host, status) in hosts.iter().zip(statuses) {
+        match status {
+            Ok(status) => println!(
+                "{:<name_width$}  {:<28}  {:<18}  {:<24}  {}",
+                host.name,
+                status.git_summary(),
+                status.generation_summary(),
+                status.nixos_version.as_deref().unwrap_or("?"),
+                if status.reboot_pending {
+                    "pending"
+                } else {
+                    "-"
+                }
+            ),
+            Err(e) => println!("{:<name_width$}  error: {}", host.name, e),
         }
     }
-
-    Ok((hostname.to_string(), success, output))
 }
 
-fn run_tui() -> Result<Vec<String>> {
+/// Let the user pick which planned hosts to deploy
+///
+/// Hosts whose plan failed or found no changes start out deselected.
+fn run_plan_confirmation(servers: Vec<Host>, plans: &[UpdateResult]) -> Result<Vec<Host>> {
     enable_raw_mode()?;
     crossterm::execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
 
     let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
 
-    let nixos_servers = get_nixos_servers()?;
-    let mut selector = ServerSelector::new(nixos_servers);
+    let mut selector = ServerSelector::new(servers);
+    selector.selected = plans
+        .iter()
+        .map(|p| p.success && p.plan.as_ref().is_some_and(|d| !d.is_empty()))
+        .collect();
 
     let result = loop {
         terminal.draw(|frame| {
             let area = frame.area();
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([
+                    Constraint::Percentage(40),
+                    Constraint::Min(3),
+                    Constraint::Length(2),
+                ])
+                .split(area);
 
             let items: Vec<ListItem> = selector
                 .servers
                 .iter()
+                .zip(plans)
                 .enumerate()
-                .map(|(i, server)| {
+                .map(|(i, (server, plan))| {
                     let prefix = if selector.selected[i] { "[X] " } else { "[ ] " };
-                    ListItem::new(format!("{}{}", prefix, server))
+                    let (summary, color) = match &plan.plan {
+                        Some(diff) if diff.is_empty() => (diff.summary(), Color::Gray),
+                        Some(diff) => (diff.summary(), Color::Reset),
+                        None => ("plan failed".to_string(), Color::Red),
+                    };
+                    ListItem::new(format!("{}{}: {}", prefix, server.name, summary))
+                        .style(Style::default().fg(color))
                 })
                 .collect();
 
             let list = List::new(items)
-                .block(
-                    Block::default()
-                        .title("NixOS Servers")
-                        .borders(Borders::ALL),
-                )
+                .block(Block::default().title("Plan").borders(Borders::ALL))
                 .highlight_style(Style::default().fg(Color::Yellow))
                 .highlight_symbol("> ");
-
-            frame.render_stateful_widget(list, area, &mut selector.state);
-
-            let help_text = "\nPress Space to select, A to toggle all, Enter to confirm, Q to quit";
-            let help_paragraph =
-                Paragraph::new(help_text).block(Block::default().borders(Borders::NONE));
-
-            let help_area = Rect::new(area.x, area.bottom() - 2, area.width, 2);
-
-            frame.render_widget(help_paragraph, help_area);
+            frame.render_stateful_widget(list, chunks[0], &mut selector.state);
+
+            let highlighted = selector.state.selected().unwrap_or(0);
+            let (title, details) = match plans.get(highlighted) {
+                Some(UpdateResult {
+                    plan: Some(diff), ..
+                }) => (
+                    format!("Changes: {}", selector.servers[highlighted].name),
+                    diff.detail_lines().join("\n"),
+                ),
+                Some(plan) => (
+                    format!("Output: {}", plan.hostname),
+                    plan.output.clone(),
+                ),
+                None => (String::new(), String::new()),
+            };
+            let details = Paragraph::new(details)
+                .block(Block::default().title(title).borders(Borders::ALL))
+                .wrap(Wrap { trim: false });
+            frame.render_widget(details, chunks[1]);
+
+            let help_text =
+                "Press Space to select, A to toggle all, Enter to deploy the selected hosts, Q to quit";
+            frame.render_widget(Paragraph::new(help_text), chunks[2]);
         })?;
 
-        if let Event::Key(key) = event::read()? {
-            if key.kind == KeyEventKind::Press {
-                match key.code {
-                    KeyCode::Char('q') => break Vec::new(), // Cancel operation
-                    KeyCode::Char(' ') => selector.toggle_selected(),
-                    KeyCode::Char('a') => selector.toggle_all(),
-                    KeyCode::Down => selector.next(),
-                    KeyCode::Up => selector.previous(),
-                    KeyCode::Enter => break selector.get_selected_servers(),
-                    _ => {}
-                }
+        if let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            match key.code {
+                KeyCode::Char('q') => break Vec::new(), // Cancel operation
+                KeyCode::Char(' ') => selector.toggle_selected(),
+                KeyCode::Char('a') => selector.toggle_all(),
+                KeyCode::Down => selector.next(),
+                KeyCode::Up => selector.previous(),
+                KeyCode::Enter => break selector.get_selected_servers(),
+                _ => {}
             }
         }
     };
@@ -351,92 +739,104 @@ fn run_tui() -> Result<Vec<String>> {
     Ok(result)
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-
-    let selected_servers = run_tui()?;
+/// Build and diff the new system of every host
+///
+/// Progress is shown in the progress TUI, which closes by itself once every
+/// plan is done, or printed line by line without a terminal.
+fn run_plan(
+    rt: &Runtime,
+    servers: &[Host],
+    options: UpdateOptions,
+    strategy: Strategy,
+    interactive: bool,
+) -> Result<(Vec<UpdateResult>, progress::ProgressMap)> {
+    let hostnames: Vec<String> = servers.iter().map(|s| s.name.clone()).collect();
+    let progress_map = create_progress_map(&hostnames);
+    let (progress_tx, progress_rx) = mpsc::channel(1000);
 
-    if selected_servers.is_empty() {
-        println!("No servers selected. Exiting.");
-        return Ok(());
-    }
+    let monitor_map = progress_map.clone();
+    let monitor_handle = rt.spawn(async move {
+        if interactive {
+            progress_monitor_task(progress_rx, monitor_map).await;
+        } else {
+            plain_progress_task(progress_rx, monitor_map).await;
+        }
+    });
 
-    let use_boot = args.boot;
-    let forward_agent = args.forward_agent;
-    let command = args.command.clone();
-    let run_after = args.after;
+    let plan_handle = rt.spawn(strategy::deploy(
+        servers.to_vec(),
+        options,
+        strategy,
+        progress_tx,
+    ));
+
+    if interactive {
+        enable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+
+        let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+        let mut progress_tui = ProgressTui::new(servers.iter().map(|s| s.label()).collect())
+            .with_notice("planning, nothing is changed".to_string())
+            .without_rollback();
+
+        let tui_result: Result<()> = loop {
+            if progress_tui.check_all_complete(&progress_map) {
+                break Ok(());
+            }
 
-    // Create progress tracking infrastructure
-    let progress_map = create_progress_map(&selected_servers);
-    let (progress_tx, progress_rx) = mpsc::channel(1000);
+            terminal.draw(|frame| {
+                progress_tui.render(frame, &progress_map);
+            })?;
 
-    let rt = Runtime::new()?;
+            if progress_tui.handle_input()? {
+                break Err(anyhow::anyhow!("Plan aborted"));
+            }
+        };
 
-    // Spawn the progress monitor task
-    let monitor_map = progress_map.clone();
-    rt.spawn(async move {
-        progress_monitor_task(progress_rx, monitor_map).await;
-    });
+        disable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+        tui_result?;
+    }
 
-    // Spawn update tasks
-    let update_handles: Vec<_> = selected_servers
-        .iter()
-        .map(|server| {
-            let server_clone = server.clone();
-            let cmd_clone = command.clone();
-            let tx = progress_tx.clone();
-            rt.spawn(async move {
-                let hostname = server_clone
-                    .split(':')
-                    .next()
-                    .unwrap_or(&server_clone)
-                    .to_string();
-                match update_server_with_progress(
-                    &server_clone,
-                    use_boot,
-                    forward_agent,
-                    cmd_clone,
-                    run_after,
-                    tx.clone(),
-                )
-                .await
-                {
-                    Ok((hostname, success, output)) => (hostname, success, output),
-                    Err(e) => {
-                        let error_msg = format!("Error: {}", e);
-                        // Send error to TUI immediately
-                        let _ = tx.try_send(progress::ProgressUpdate {
-                            hostname: hostname.clone(),
-                            phase: progress::UpdatePhase::Failed {
-                                reason: error_msg.clone(),
-                            },
-                            output_line: Some(error_msg.clone()),
-                        });
-                        (hostname, false, error_msg)
-                    }
-                }
-            })
-        })
-        .collect();
+    let results = rt.block_on(plan_handle)?;
+    let _ = rt.block_on(monitor_handle);
 
-    // Drop the original sender so the monitor task can complete
-    drop(progress_tx);
+    Ok((results, progress_map))
+}
 
-    // Run the progress TUI
+/// Show the progress TUI until the user quits
+///
+/// Rollbacks requested from the TUI are started on `rt` and their handles
+/// returned.
+fn run_progress_tui(
+    selected_servers: &[Host],
+    progress_map: &progress::ProgressMap,
+    rt: &Runtime,
+    options: &UpdateOptions,
+    progress_tx: mpsc::Sender<ProgressUpdate>,
+) -> Result<Vec<JoinHandle<UpdateResult>>> {
     enable_raw_mode()?;
     crossterm::execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
 
     let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
-    let mut progress_tui =
-        ProgressTui::new(selected_servers.iter().map(|s| s.to_string()).collect());
+    let mut progress_tui = ProgressTui::new(selected_servers.iter().map(|s| s.label()).collect());
+    if options.operation == Operation::Deploy && !options.action.is_permanent() {
+        progress_tui = progress_tui
+            .with_notice(format!(
+                "{}: nothing is permanently changed",
+                options.action
+            ))
+            .without_rollback();
+    }
+    let mut rollback_handles = Vec::new();
 
     // TUI loop
     let tui_result: Result<()> = loop {
         // Check if all servers are done and update TUI state
-        progress_tui.check_all_complete(&progress_map);
+        progress_tui.check_all_complete(progress_map);
 
         terminal.draw(|frame| {
-            progress_tui.render(frame, &progress_map);
+            progress_tui.render(frame, progress_map);
         })?;
 
         // Check if user wants to quit
@@ -444,34 +844,573 @@ fn main() -> Result<()> {
         if progress_tui.handle_input()? {
             break Ok(());
         }
+
+        for (hostname, generation) in progress_tui.take_rollback_requests() {
+            if let Some(host) = selected_servers.iter().find(|h| h.name == hostname) {
+                let rollback_options = UpdateOptions {
+                    operation: Operation::Rollback {
+                        generation: Some(generation),
+                    },
+                    ..options.clone()
+                };
+                rollback_handles.push(rt.spawn(strategy::update_host(
+                    host.clone(),
+                    rollback_options,
+                    progress_tx.clone(),
+                )));
+            }
+        }
     };
 
     disable_raw_mode()?;
     crossterm::execute!(std::io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
 
-    tui_result?;
+    tui_result.map(|_| rollback_handles)
+}
 
-    // Wait for all update tasks to complete and collect results
-    let results = rt.block_on(async {
-        let task_results = join_all(update_handles).await;
-        task_results
-            .into_iter()
-            .map(|r| {
-                r.unwrap_or_else(|e| ("Unknown".to_string(), false, format!("Task error: {}", e)))
-            })
-            .collect::<Vec<_>>()
+/// Write the host logs and the summary of a finished run
+fn record_run(
+    run_log: &RunLog,
+    report: &DeploymentReport,
+    results: &[UpdateResult],
+    rollbacks: &[UpdateResult],
+) -> Result<()> {
+    for result in results {
+        run_log.write_host_logs(result, None)?;
+    }
+    for rollback in rollbacks {
+        run_log.write_host_logs(rollback, Some("rollback"))?;
+    }
+    run_log.write_summary(report)
+}
+
+/// History entries for the hosts of a finished run and the rollbacks
+/// started from the TUI
+fn history_entries(
+    parameters: &RunParameters,
+    report: &DeploymentReport,
+    rollbacks: &[UpdateResult],
+    run_dir: Option<PathBuf>,
+) -> Vec<HistoryEntry> {
+    let mut entries: Vec<HistoryEntry> = report
+        .hosts
+        .iter()
+        .map(|host| {
+            HistoryEntry::new(
+                parameters,
+                &host.result,
+                host.failure_reason.clone(),
+                host.phases.iter().map(|phase| phase.duration_secs).sum(),
+                run_dir.clone(),
+            )
+        })
+        .collect();
+
+    let rollback_parameters = RunParameters {
+        operation: Operation::Rollback { generation: None },
+        ..parameters.clone()
+    };
+    for rollback in rollbacks {
+        let failure_reason = rollback
+            .error
+            .as_ref()
+            .map(|error| error.summary.clone())
+            .or_else(|| Some("Rollback failed".to_string()));
+        entries.push(HistoryEntry::new(
+            &rollback_parameters,
+            rollback,
+            failure_reason,
+            rollback.commands.iter().map(|c| c.duration_secs).sum(),
+            run_dir.clone(),
+        ));
+    }
+    entries
+}
+
+/// Feed the recorded progress events of a run into the progress view
+fn run_replay(run: Option<&str>, real_time: bool, interactive: bool) -> Result<()> {
+    let dir = RunLog::find(run)?;
+    let parameters = RunLog::load_parameters(&dir)?;
+    let events_path = dir.join("events.jsonl");
+    if !events_path.exists() {
+        anyhow::bail!(
+            "{} has no recorded progress events, it was made by an older version",
+            dir.display()
+        );
+    }
+    let events = replay::load_events(&events_path)?;
+
+    let rt = Runtime::new()?;
+    let progress_map = create_progress_map(&parameters.hosts);
+    let (progress_tx, progress_rx) = mpsc::channel(1000);
+
+    let monitor_map = progress_map.clone();
+    let monitor_handle = rt.spawn(async move {
+        if interactive {
+            progress_monitor_task(progress_rx, monitor_map).await;
+        } else {
+            plain_progress_task(progress_rx, monitor_map).await;
+        }
+    });
+    let replay_handle = rt.spawn(replay::replay(events, progress_tx, real_time));
+
+    if !interactive {
+        rt.block_on(replay_handle)?;
+        let _ = rt.block_on(monitor_handle);
+        return Ok(());
+    }
+
+    enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+    let mut progress_tui = ProgressTui::new(parameters.hosts.clone())
+        .with_notice(format!(
+            "replay of {}",
+            dir.file_name().unwrap_or_default().to_string_lossy()
+        ))
+        .without_rollback();
+
+    let tui_result: Result<()> = loop {
+        progress_tui.check_all_complete(&progress_map);
+        if let Err(e) = terminal.draw(|frame| progress_tui.render(frame, &progress_map)) {
+            break Err(e.into());
+        }
+        match progress_tui.handle_input() {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    disable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), LeaveAlternateScreen, DisableMouseCapture)?;
+
+    replay_handle.abort();
+    tui_result
+}
+
+fn run_history_view(entries: Vec<HistoryEntry>) -> Result<()> {
+    enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+    let mut view = HistoryView::new(entries);
+
+    let result: Result<()> = loop {
+        if let Err(e) = terminal.draw(|frame| view.render(frame)) {
+            break Err(e.into());
+        }
+        match view.handle_input() {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    disable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), LeaveAlternateScreen)?;
+
+    result
+}
+
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    let reports = args.reports()?;
+    let interactive = std::io::stdout().is_terminal();
+
+    // The history doesn't need any hosts
+    if let Some(Commands::History {
+        host,
+        failed,
+        limit,
+    }) = &args.subcommand
+    {
+        let pattern = host.as_deref().map(HostPattern::parse).transpose()?;
+        let mut entries = history::load(pattern.as_ref(), *failed)?;
+        if let Some(limit) = limit {
+            entries.truncate(*limit);
+        }
+        if interactive {
+            run_history_view(entries)?;
+        } else {
+            history::print_history_table(&entries);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Commands::Replay { run, real_time }) = &args.subcommand {
+        run_replay(run.as_deref(), *real_time, interactive)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let inventory = match args.inventory.clone().or_else(Inventory::default_path) {
+        Some(path) => Some(Inventory::load(&path)?),
+        None => None,
+    };
+    let settings = inventory.clone().unwrap_or_default();
+
+    let include = [settings.discovery.include, args.include.clone()].concat();
+    let exclude = [settings.discovery.exclude, args.exclude.clone()].concat();
+    let filter = HostFilter::new(&include, &exclude)?;
+
+    let backends = discovery_backends(&args, inventory.as_ref(), !include.is_empty())?;
+    let mut nixos_servers = discover_hosts(&backends, &filter)?;
+    settings.flake.apply(&mut nixos_servers);
+    settings.jump.apply(&mut nixos_servers);
+    let ssh_config = match SshConfig::default_path() {
+        Ok(path) if path.exists() => SshConfig::load(&path)?,
+        _ => SshConfig::default(),
+    };
+    ssh_config.apply(&mut nixos_servers);
+    for host in &mut nixos_servers {
+        if host.user.is_none() {
+            host.user = args.ssh_user.clone();
+        }
+        if host.port.is_none() {
+            host.port = args.ssh_port;
+        }
+        for (i, identity_file) in args.identity_files.iter().enumerate() {
+            let path = paths::expand_home(identity_file)?;
+            host.identity_files.retain(|existing| *existing != path);
+            host.identity_files.insert(i, path);
+        }
+    }
+
+    let sudo_password = if args.ask_sudo_password {
+        Some(rpassword::prompt_password("sudo password: ")?)
+    } else {
+        None
+    };
+
+    // Key passphrases are asked for once the hosts are known; until then,
+    // only the keys the SSH agent holds and unencrypted ones are used
+    let mut connect = ConnectOptions {
+        host_key_checking: args.host_key_checking,
+        ssh_config,
+        jumps: Default::default(),
+        passphrases: Default::default(),
+    };
+
+    let rt = Runtime::new()?;
+
+    if let Some(Commands::Status) = args.subcommand {
+        let hosts = if args.is_headless() {
+            select_hosts(&args, nixos_servers)?
+        } else {
+            nixos_servers
+        };
+        connect.passphrases = identity::ask_passphrases(&hosts);
+        let statuses = rt.block_on(query_statuses(
+            &hosts,
+            &connect,
+            args.forward_agent,
+            sudo_password.as_deref(),
+        ));
+        print_status_table(&hosts, &statuses);
+
+        return Ok(if statuses.iter().all(Result::is_ok) {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        });
+    }
+
+    let mut selected_servers = if args.is_headless() {
+        let selected = select_hosts(&args, nixos_servers)?;
+        if selected.is_empty() {
+            bail!("No hosts matched --hosts/--tag/--all");
+        }
+        selected
+    } else if interactive {
+        let statuses = args.status.then(|| {
+            rt.block_on(query_statuses(
+                &nixos_servers,
+                &connect,
+                args.forward_agent,
+                sudo_password.as_deref(),
+            ))
+        });
+        run_tui(nixos_servers, statuses.as_deref())?
+    } else {
+        bail!("stdout is not a terminal; select hosts with --hosts, --tag or --all");
+    };
+
+    if selected_servers.is_empty() {
+        println!("No servers selected. Exiting.");
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Before any progress view takes over the terminal
+    connect.passphrases = identity::ask_passphrases(&selected_servers);
+
+    let operation = match args.subcommand {
+        Some(Commands::Rollback { generation }) => Operation::Rollback { generation },
+        Some(Commands::Status) => unreachable!("status returns before deploying"),
+        Some(Commands::History { .. }) => unreachable!("history returns before deploying"),
+        Some(Commands::Replay { .. }) => unreachable!("replay returns before deploying"),
+        None => Operation::Deploy,
+    };
+
+    let options = UpdateOptions {
+        operation,
+        action: if args.boot {
+            RebuildAction::Boot
+        } else {
+            args.action
+        },
+        forward_agent: args.forward_agent,
+        connect,
+        command: args.command.clone(),
+        run_after: args.after,
+        local_build: args.local_build.then(|| LocalBuild {
+            flake: args.flake.clone(),
+            build_host: args.build_host.clone(),
+        }),
+        magic_rollback: args
+            .magic_rollback
+            .then(|| std::time::Duration::from_secs(args.confirm_timeout)),
+        reboot: args
+            .reboot
+            .then(|| std::time::Duration::from_secs(args.reboot_timeout)),
+        health: if args.skip_health_checks {
+            HealthSettings::default()
+        } else {
+            settings.health.clone()
+        },
+        sudo_password,
+    };
+
+    if options.reboot.is_some() && options.action != RebuildAction::Boot {
+        bail!("--reboot requires --boot or --action boot");
+    }
+
+    let strategy = Strategy {
+        parallel: args.parallel.map(|n| n as usize),
+        waves: args.waves.clone(),
+        canary: args.canary,
+        stop_on_failure: args.stop_on_failure,
+    };
+
+    if args.plan {
+        if operation != Operation::Deploy {
+            bail!("--plan only applies to deployments");
+        }
+
+        let plan_options = UpdateOptions {
+            operation: Operation::Plan,
+            ..options.clone()
+        };
+        let plan_started_at = chrono::Utc::now();
+        let plan_strategy = Strategy {
+            parallel: strategy.parallel,
+            ..Strategy::default()
+        };
+        let (plans, plan_progress) = run_plan(
+            &rt,
+            &selected_servers,
+            plan_options,
+            plan_strategy,
+            interactive,
+        )?;
+
+        if !interactive {
+            println!("\n=== Plan ===");
+            for plan in &plans {
+                match &plan.plan {
+                    Some(diff) => {
+                        println!("{}: {}", plan.hostname, diff.summary());
+                        for line in diff.detail_lines() {
+                            println!("   {}", line);
+                        }
+                    }
+                    None => println!("{}: plan failed", plan.hostname),
+                }
+            }
+
+            let report = DeploymentReport::new(plan_started_at, &plans, &plan_progress);
+            for (format, path) in &reports {
+                report.write(*format, path)?;
+                println!("Report written to {}", path.display());
+            }
+
+            return Ok(if plans.iter().all(|p| p.success) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            });
+        }
+
+        selected_servers = run_plan_confirmation(selected_servers, &plans)?;
+        if selected_servers.is_empty() {
+            println!("No servers selected. Exiting.");
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+
+    let started_at = chrono::Utc::now();
+
+    // A run that can't be recorded still goes ahead
+    let parameters = RunParameters {
+        started_at,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: std::env::args().collect(),
+        user: std::env::var("USER").ok(),
+        operation,
+        action: options.action.to_string(),
+        hosts: selected_servers.iter().map(|s| s.name.clone()).collect(),
+        local_build: options.local_build.is_some(),
+    };
+    let run_log = match RunLog::create(&parameters) {
+        Ok(run_log) => Some(run_log),
+        Err(e) => {
+            eprintln!("Warning: not recording this run: {:#}", e);
+            None
+        }
+    };
+
+    // Create progress tracking infrastructure
+    let hostnames: Vec<String> = selected_servers.iter().map(|s| s.name.clone()).collect();
+    let progress_map = create_progress_map(&hostnames);
+    let (progress_tx, progress_rx) = mpsc::channel(1000);
+
+    // Keep the updates for `replay`
+    let progress_rx = match run_log
+        .as_ref()
+        .map(|run_log| EventRecorder::create(&run_log.events_path()))
+    {
+        Some(Ok(recorder)) => recorder.tap(&rt, progress_rx),
+        Some(Err(e)) => {
+            eprintln!("Warning: not recording progress events: {:#}", e);
+            progress_rx
+        }
+        None => progress_rx,
+    };
+
+    // Spawn the progress monitor task, printing lines when there is no terminal
+    let monitor_map = progress_map.clone();
+    let monitor_handle = rt.spawn(async move {
+        if interactive {
+            progress_monitor_task(progress_rx, monitor_map).await;
+        } else {
+            plain_progress_task(progress_rx, monitor_map).await;
+        }
     });
 
-    // Print final summary
+    // Run the updates; the monitor task completes once the deployment and the
+    // TUI have dropped their senders
+    let deploy_handle = rt.spawn(strategy::deploy(
+        selected_servers.clone(),
+        options.clone(),
+        strategy,
+        progress_tx.clone(),
+    ));
+
+    let rollback_handles = if interactive {
+        run_progress_tui(&selected_servers, &progress_map, &rt, &options, progress_tx)?
+    } else {
+        drop(progress_tx);
+        Vec::new()
+    };
+
+    // Wait for all updates to complete and collect results
+    let results = rt.block_on(deploy_handle)?;
+    let rollbacks: Vec<UpdateResult> = rt
+        .block_on(join_all(rollback_handles))
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Let the monitor drain the remaining updates before printing the summary
+    let _ = rt.block_on(monitor_handle);
+
+    // Print final summary, starting with why hosts failed
     println!("\n=== Update Summary ===");
-    for (hostname, success, output) in results {
-        if success {
-            println!("✅ {}: Update successful", hostname);
+    for result in &results {
+        if let Some(error) = result.error.as_ref().filter(|_| !result.success) {
+            println!("❌ {}: {}", result.hostname, error.summary);
+            for line in &error.details {
+                println!("   {}", line);
+            }
+        }
+    }
+    let mut all_succeeded = true;
+    for result in &results {
+        if result.success {
+            match (operation, result.generation) {
+                (Operation::Rollback { .. }, Some(generation)) => println!(
+                    "✅ {}: Rolled back, generation {} is active",
+                    result.hostname, generation
+                ),
+                _ if !options.action.is_permanent() => println!(
+                    "✅ {}: {} successful, nothing was permanently changed",
+                    result.hostname, options.action
+                ),
+                _ if !result.reboot_required.is_empty() => println!(
+                    "✅ {}: Update successful, reboot required ({} changed)",
+                    result.hostname,
+                    result.reboot_required.join(", ")
+                ),
+                _ => println!("✅ {}: Update successful", result.hostname),
+            }
+            if let Some(changes) = &result.dry_activation {
+                for line in changes.summary_lines() {
+                    println!("   {}", line);
+                }
+            }
+        } else if result.skipped {
+            all_succeeded = false;
+            println!("⏭️ {}: Skipped", result.hostname);
         } else {
-            println!("❌ {}: Update failed", hostname);
-            println!("Output:\n{}", output);
+            all_succeeded = false;
+            match operation {
+                Operation::Rollback { .. } => println!("❌ {}: Rollback failed", result.hostname),
+                Operation::Deploy | Operation::Plan => {
+                    println!("❌ {}: Update failed", result.hostname)
+                }
+            }
+            // Plain mode already streamed the output line by line
+            if interactive {
+                println!("Output:\n{}", result.output);
+            }
+        }
+    }
+
+    // Rollbacks started from the TUI
+    for rollback in &rollbacks {
+        match (rollback.success, rollback.generation) {
+            (true, Some(generation)) => println!(
+                "↩️ {}: Rolled back, generation {} is active",
+                rollback.hostname, generation
+            ),
+            (true, None) => println!("↩️ {}: Rolled back", rollback.hostname),
+            (false, _) => {
+                all_succeeded = false;
+                println!("❌ {}: Rollback failed", rollback.hostname);
+                println!("Output:\n{}", rollback.output);
+            }
         }
     }
 
-    Ok(())
+    let report = DeploymentReport::new(started_at, &results, &progress_map);
+    for (format, path) in &reports {
+        report.write(*format, path)?;
+        println!("Report written to {}", path.display());
+    }
+    if let Some(run_log) = &run_log {
+        match record_run(run_log, &report, &results, &rollbacks) {
+            Ok(()) => println!("Run logs written to {}", run_log.path().display()),
+            Err(e) => eprintln!("Warning: failed to record the run: {:#}", e),
+        }
+    }
+    let run_dir = run_log.as_ref().map(|run_log| run_log.path().to_path_buf());
+    if let Err(e) = history::append(&history_entries(&parameters, &report, &rollbacks, run_dir)) {
+        eprintln!("Warning: failed to update the deployment history: {:#}", e);
+    }
+
+    Ok(if all_succeeded {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
 }
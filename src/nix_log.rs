@@ -0,0 +1,325 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::progress::NixProgress;
+
+/// Arguments that make nix (and nixos-rebuild) log in the format `NixLog` reads
+pub const LOG_FORMAT_ARGS: [&str; 2] = ["--log-format", "internal-json"];
+
+/// Prefix of every structured line nix writes with `--log-format internal-json`
+const EVENT_PREFIX: &str = "@nix ";
+
+/// Messages above this level (`info`) are hidden, as nix does without `-v`
+const MAX_LEVEL: u64 = 3;
+
+/// Progress-only updates are sent at most this often
+const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+// Activity types, see `ActivityType` in nix's logging.hh
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+
+// Result types, see `ResultType` in nix's logging.hh
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_PROGRESS: u64 = 105;
+
+/// One `@nix` event
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Event {
+    Start {
+        id: u64,
+        #[serde(default)]
+        level: u64,
+        #[serde(rename = "type", default)]
+        kind: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Msg {
+        #[serde(default)]
+        level: u64,
+        msg: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug)]
+struct Activity {
+    kind: u64,
+    /// Derivation name, for the log lines of builds
+    name: Option<String>,
+    done: u64,
+    expected: u64,
+}
+
+/// Reads the output of a nix command run with `LOG_FORMAT_ARGS`
+///
+/// Structured lines update the progress counters and are turned back into
+/// the lines nix would have printed; any other line is kept as it is.
+#[derive(Debug, Default)]
+pub struct NixLog {
+    activities: HashMap<u64, Activity>,
+    /// Progress of stopped activities, by activity type
+    finished: HashMap<u64, u64>,
+    reported: NixProgress,
+    last_report: Option<Instant>,
+}
+
+impl NixLog {
+    /// Process one line of output and return what should be shown for it
+    pub fn parse(&mut self, line: &str) -> Option<String> {
+        let Some(json) = line.strip_prefix(EVENT_PREFIX) else {
+            return Some(line.to_string());
+        };
+        let Ok(event) = serde_json::from_str::<Event>(json) else {
+            return Some(line.to_string());
+        };
+
+        let text = match event {
+            Event::Start {
+                id,
+                level,
+                kind,
+                text,
+                fields,
+            } => {
+                let name = (kind == ACT_BUILD)
+                    .then(|| fields.first().and_then(|f| f.as_str()).map(derivation_name))
+                    .flatten();
+                self.activities.insert(
+                    id,
+                    Activity {
+                        kind,
+                        name,
+                        done: 0,
+                        expected: 0,
+                    },
+                );
+                (level <= MAX_LEVEL && !text.is_empty()).then_some(text)
+            }
+            Event::Stop { id } => {
+                if let Some(activity) = self.activities.remove(&id) {
+                    *self.finished.entry(activity.kind).or_default() += activity.done;
+                }
+                None
+            }
+            Event::Result { id, kind, fields } => {
+                let activity = self.activities.get_mut(&id)?;
+                match kind {
+                    RES_PROGRESS => {
+                        let number = |i: usize| fields.get(i).and_then(|f| f.as_u64()).unwrap_or(0);
+                        activity.done = number(0);
+                        activity.expected = number(1);
+                        None
+                    }
+                    RES_BUILD_LOG_LINE => {
+                        let text = fields.first()?.as_str()?;
+                        Some(match &activity.name {
+                            Some(name) => format!("{}> {}", name, text),
+                            None => text.to_string(),
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            Event::Msg { level, msg } => (level <= MAX_LEVEL).then_some(msg),
+            Event::Other => None,
+        }?;
+
+        // Errors and build logs come with colors
+        let stripped = strip_ansi_escapes::strip(text.as_bytes());
+        Some(String::from_utf8_lossy(&stripped).to_string())
+    }
+
+    /// Counters over all activities seen so far
+    pub fn progress(&self) -> NixProgress {
+        let (builds_done, builds_expected) = self.totals(ACT_BUILDS);
+        let (substitutions_done, substitutions_expected) = self.totals(ACT_COPY_PATHS);
+        NixProgress {
+            builds_done,
+            builds_expected,
+            substitutions_done,
+            substitutions_expected,
+            bytes_downloaded: self.totals(ACT_FILE_TRANSFER).0,
+        }
+    }
+
+    fn totals(&self, kind: u64) -> (u64, u64) {
+        let finished = self.finished.get(&kind).copied().unwrap_or(0);
+        let (done, expected) = self
+            .activities
+            .values()
+            .filter(|activity| activity.kind == kind)
+            .fold((0, 0), |(done, expected), activity| {
+                (done + activity.done, expected + activity.expected)
+            });
+        (finished + done, finished + expected.max(done))
+    }
+
+    /// Whether the progress changed enough to send it without an output line
+    ///
+    /// Downloads report progress many times a second, so these updates are
+    /// rate limited to keep the progress channel free for log lines.
+    pub fn should_report(&mut self, with_line: bool) -> bool {
+        let progress = self.progress();
+        let due = self
+            .last_report
+            .is_none_or(|last| last.elapsed() >= REPORT_INTERVAL);
+        if !with_line && (progress == self.reported || !due) {
+            return false;
+        }
+        self.reported = progress;
+        self.last_report = Some(Instant::now());
+        true
+    }
+}
+
+/// `hello-2.12` for `/nix/store/<hash>-hello-2.12.drv`
+pub fn derivation_name(path: &str) -> String {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    let file = file.strip_suffix(".drv").unwrap_or(file);
+    match file.split_once('-') {
+        Some((_, name)) => name.to_string(),
+        None => file.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_through_plain_and_malformed_lines() {
+        let mut log = NixLog::default();
+        let cases = [
+            "building the system configuration...",
+            "@nix not json",
+            "@nix {\"action\":\"start\"}",
+        ];
+        for line in cases {
+            assert_eq!(log.parse(line).as_deref(), Some(line));
+        }
+        assert!(log.progress().is_empty());
+    }
+
+    #[test]
+    fn shows_messages_up_to_info() {
+        let mut log = NixLog::default();
+        let cases = [
+            (
+                r#"@nix {"action":"msg","level":0,"msg":"error: \u001b[31;1mboom\u001b[0m"}"#,
+                Some("error: boom"),
+            ),
+            (
+                r#"@nix {"action":"msg","level":3,"msg":"info"}"#,
+                Some("info"),
+            ),
+            (r#"@nix {"action":"msg","level":5,"msg":"chatty"}"#, None),
+            (
+                r#"@nix {"action":"start","id":1,"level":3,"type":0,"text":"evaluating"}"#,
+                Some("evaluating"),
+            ),
+            (
+                r#"@nix {"action":"start","id":2,"level":4,"type":0,"text":"verbose"}"#,
+                None,
+            ),
+            (r#"@nix {"action":"setPhase","id":1,"phase":"build"}"#, None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(log.parse(line).as_deref(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn prefixes_build_log_lines_with_derivation() {
+        let mut log = NixLog::default();
+        log.parse(r#"@nix {"action":"start","id":7,"level":3,"type":105,"text":"building","fields":["/nix/store/abc-hello-2.12.drv","",1,1]}"#);
+        assert_eq!(
+            log.parse(r#"@nix {"action":"result","id":7,"type":101,"fields":["compiling"]}"#)
+                .as_deref(),
+            Some("hello-2.12> compiling")
+        );
+        // Results for unknown activities are dropped
+        assert_eq!(
+            log.parse(r#"@nix {"action":"result","id":8,"type":101,"fields":["lost"]}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn counts_progress_across_activities() {
+        let mut log = NixLog::default();
+        for line in [
+            r#"@nix {"action":"start","id":1,"level":0,"type":104}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[1,3,0,0]}"#,
+            r#"@nix {"action":"start","id":2,"level":0,"type":103}"#,
+            r#"@nix {"action":"result","id":2,"type":105,"fields":[2,2,0,0]}"#,
+            r#"@nix {"action":"start","id":3,"level":0,"type":101}"#,
+            r#"@nix {"action":"result","id":3,"type":105,"fields":[1024,4096,0,0]}"#,
+        ] {
+            assert_eq!(log.parse(line), None);
+        }
+        assert_eq!(
+            log.progress(),
+            NixProgress {
+                builds_done: 1,
+                builds_expected: 3,
+                substitutions_done: 2,
+                substitutions_expected: 2,
+                bytes_downloaded: 1024,
+            }
+        );
+
+        // Stopped activities keep what they did, but no longer expect more
+        log.parse(r#"@nix {"action":"stop","id":1}"#);
+        log.parse(r#"@nix {"action":"start","id":4,"level":0,"type":104}"#);
+        log.parse(r#"@nix {"action":"result","id":4,"type":105,"fields":[0,2,0,0]}"#);
+        let progress = log.progress();
+        assert_eq!((progress.builds_done, progress.builds_expected), (1, 3));
+    }
+
+    #[test]
+    fn reports_progress_only_when_it_changed() {
+        let mut log = NixLog::default();
+        assert!(log.should_report(true));
+        assert!(!log.should_report(false));
+
+        log.parse(r#"@nix {"action":"start","id":1,"level":0,"type":104}"#);
+        log.parse(r#"@nix {"action":"result","id":1,"type":105,"fields":[0,1,0,0]}"#);
+        // Changed, but the last report was just now
+        assert!(!log.should_report(false));
+        assert!(log.should_report(true));
+    }
+
+    #[test]
+    fn derivation_names() {
+        let cases = [
+            ("/nix/store/0123abcd-hello-2.12.drv", "hello-2.12"),
+            (
+                "0123abcd-nixos-system-web-24.05.drv",
+                "nixos-system-web-24.05",
+            ),
+            ("plain", "plain"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(derivation_name(path), expected);
+        }
+    }
+}
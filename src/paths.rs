@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+/// The user's home directory, from `$HOME`
+pub fn home_dir() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .context("HOME is not set")
+}
+
+/// `$XDG_CONFIG_HOME/nix-deploy`, or `~/.config/nix-deploy`
+pub fn config_dir() -> Result<PathBuf> {
+    Ok(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("nix-deploy"))
+}
+
+/// `$XDG_STATE_HOME/nix-deploy`, or `~/.local/state/nix-deploy`
+pub fn state_dir() -> Result<PathBuf> {
+    Ok(xdg_dir("XDG_STATE_HOME", ".local/state")?.join("nix-deploy"))
+}
+
+/// The XDG base directory in `var`, falling back to `fallback` in the home
+/// directory
+fn xdg_dir(var: &str, fallback: &str) -> Result<PathBuf> {
+    match std::env::var_os(var).filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(home_dir()?.join(fallback)),
+    }
+}
+
+/// Expand a leading `~/` to the home directory
+pub fn expand_home(path: &str) -> Result<PathBuf> {
+    match path.strip_prefix("~/") {
+        Some(rest) => Ok(home_dir()?.join(rest)),
+        None => Ok(PathBuf::from(path)),
+    }
+}
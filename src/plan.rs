@@ -0,0 +1,349 @@
+use anyhow::Result;
+use serde::Serialize;
+use ssh2::Session;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::closure::copy_closure;
+use crate::host::Host;
+use crate::nix_log::LOG_FORMAT_ARGS;
+use crate::progress::{NixProgress, ProgressUpdate, UpdatePhase};
+use crate::ssh_executor::{
+    Sudo, execute_command_on_channel, execute_command_streaming, shell_quote,
+};
+use crate::updater::{UpdateOptions, UpdateResult, connect_session, fetch_upstream_revision};
+
+/// What deploying the new configuration would change on a host, from
+/// `nix store diff-closures /run/current-system <new toplevel>`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClosureDiff {
+    pub current_system: String,
+    pub new_system: String,
+    /// Lines of packages that are new, e.g. `foo: ∅ → 1.2, +1.5 MiB`
+    pub added: Vec<String>,
+    /// Lines of packages that are gone, e.g. `foo: 1.2 → ∅, -1.5 MiB`
+    pub removed: Vec<String>,
+    /// Lines of packages with a new version or size
+    pub changed: Vec<String>,
+    /// Change of the closure size in bytes
+    pub size_delta: i64,
+}
+
+impl ClosureDiff {
+    pub fn parse(current_system: &str, new_system: &str, output: &str) -> Self {
+        let stripped = strip_ansi_escapes::strip(output.as_bytes());
+        let output = String::from_utf8_lossy(&stripped);
+
+        let mut diff = Self {
+            current_system: current_system.to_string(),
+            new_system: new_system.to_string(),
+            ..Self::default()
+        };
+
+        for line in output.lines().map(str::trim) {
+            let Some((_, changes)) = line.split_once(": ") else {
+                continue;
+            };
+
+            let mut versions = None;
+            for part in changes.split(", ") {
+                if part.contains('→') {
+                    versions = Some(part);
+                } else if let Some(size) = parse_size(part) {
+                    diff.size_delta += size;
+                }
+            }
+
+            match versions {
+                Some(v) if v.starts_with('∅') => diff.added.push(line.to_string()),
+                Some(v) if v.ends_with('∅') => diff.removed.push(line.to_string()),
+                _ => diff.changed.push(line.to_string()),
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current_system == self.new_system
+            || (self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty())
+    }
+
+    /// One line overview, e.g. `2 added, 1 removed, 5 changed, +12.3 MiB`
+    pub fn summary(&self) -> String {
+        if self.is_empty() {
+            return "no changes".to_string();
+        }
+
+        format!(
+            "{} added, {} removed, {} changed, {}",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            format_size(self.size_delta)
+        )
+    }
+
+    /// Every changed package, grouped by kind
+    pub fn detail_lines(&self) -> Vec<String> {
+        self.added
+            .iter()
+            .map(|l| format!("+ {}", l))
+            .chain(self.removed.iter().map(|l| format!("- {}", l)))
+            .chain(self.changed.iter().map(|l| format!("~ {}", l)))
+            .collect()
+    }
+}
+
+/// `+12.3 KiB` as bytes
+fn parse_size(text: &str) -> Option<i64> {
+    let (number, unit) = text.trim().split_once(' ')?;
+    let value: f64 = number.parse().ok()?;
+    let factor = match unit {
+        "B" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((value * factor) as i64)
+}
+
+pub fn format_size(bytes: i64) -> String {
+    let sign = if bytes < 0 { "-" } else { "+" };
+    let mut value = bytes.unsigned_abs() as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if value < 1024.0 {
+            return format!("{}{:.1} {}", sign, value, unit);
+        }
+        value /= 1024.0;
+    }
+    format!("{}{:.1} TiB", sign, value)
+}
+
+/// Build the new toplevel on the host from the upstream revision of
+/// /etc/nixos, without touching the checkout
+fn build_on_host(
+    sess: &Session,
+    host: &Host,
+    options: &UpdateOptions,
+    sudo: &Sudo,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<Option<String>> {
+    let hostname = host.name.as_str();
+
+    let revision =
+        match fetch_upstream_revision(sess, options.forward_agent, sudo, progress_tx, result)? {
+            Ok(revision) => revision,
+            Err(error_msg) => anyhow::bail!(error_msg),
+        };
+
+    let phase = UpdatePhase::Rebuilding {
+        progress: NixProgress::default(),
+    };
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: phase.clone(),
+        output_line: Some(format!("Building revision {}...", revision)),
+    });
+
+    let installable = format!(
+        "git+file:///etc/nixos?rev={}#nixosConfigurations.\"{}\".config.system.build.toplevel",
+        revision,
+        host.flake_attribute()
+    );
+    let build_cmd = format!(
+        "nix --extra-experimental-features 'nix-command flakes' build --no-link --print-out-paths {} {}",
+        LOG_FORMAT_ARGS.join(" "),
+        shell_quote(&installable)
+    );
+
+    let started = Instant::now();
+    let (output, exit_status) = execute_command_streaming(
+        sess,
+        &build_cmd,
+        options.forward_agent,
+        sudo,
+        progress_tx,
+        hostname,
+        &phase,
+        true,
+    )?;
+    result.record_command(&build_cmd, &output, exit_status, started);
+    if exit_status != 0 {
+        anyhow::bail!("nix build failed with exit code: {}", exit_status);
+    }
+
+    let stripped = strip_ansi_escapes::strip(output.as_bytes());
+    Ok(String::from_utf8_lossy(&stripped)
+        .lines()
+        .map(str::trim)
+        .rfind(|line| line.starts_with("/nix/store/"))
+        .map(str::to_string))
+}
+
+/// Build the new system for the host and compare it to the running one
+///
+/// With a local build, the closure is copied to the host, so the deployment
+/// that follows doesn't have to copy it again.
+pub fn plan_server_blocking(
+    host: &Host,
+    options: &UpdateOptions,
+    progress_tx: mpsc::Sender<ProgressUpdate>,
+) -> Result<UpdateResult> {
+    let hostname = host.name.as_str();
+    let forward_agent = options.forward_agent;
+    let sudo = Sudo::for_host(host, options.sudo_password.as_deref());
+    let mut result = UpdateResult::new(hostname);
+
+    let local_toplevel = match &options.local_build {
+        Some(local_build) => {
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::Building {
+                    progress: NixProgress::default(),
+                },
+                output_line: Some(format!(
+                    "Building {}#{}...",
+                    local_build.flake,
+                    host.flake_attribute()
+                )),
+            });
+
+            match local_build.build_toplevel(host.flake_attribute(), &progress_tx, &mut result) {
+                Ok(toplevel) => Some(toplevel),
+                Err(e) => {
+                    let error_msg = format!("Local build failed: {}", e);
+                    return Ok(result.fail(error_msg, &progress_tx));
+                }
+            }
+        }
+        None => None,
+    };
+
+    let sess = match connect_session(
+        host,
+        &options.connect,
+        Duration::from_secs(60),
+        &progress_tx,
+    )? {
+        Ok(sess) => sess,
+        Err(error_msg) => return Ok(result.fail(error_msg, &progress_tx)),
+    };
+
+    let new_system = match local_toplevel {
+        Some(toplevel) => {
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::CopyingClosure {
+                    progress: String::new(),
+                },
+                output_line: Some(format!("Copying closure of {}...", toplevel)),
+            });
+            if let Err(e) = copy_closure(&sess, &toplevel, &sudo, &progress_tx, &mut result) {
+                let error_msg = format!("Copying closure failed: {}", e);
+                return Ok(result.fail(error_msg, &progress_tx));
+            }
+            toplevel
+        }
+        None => match build_on_host(&sess, host, options, &sudo, &progress_tx, &mut result) {
+            Ok(Some(toplevel)) => toplevel,
+            Ok(None) => {
+                let error_msg = "nix build did not print an output path".to_string();
+                return Ok(result.fail(error_msg, &progress_tx));
+            }
+            Err(e) => return Ok(result.fail(e.to_string(), &progress_tx)),
+        },
+    };
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::Planning,
+        output_line: Some("Comparing with the running system...".to_string()),
+    });
+
+    let (current_system, _) =
+        execute_command_on_channel(&sess, "readlink -f /run/current-system", forward_agent)?;
+    let current_system = current_system.trim().to_string();
+
+    let diff_cmd = format!(
+        "nix --extra-experimental-features nix-command store diff-closures /run/current-system {}",
+        shell_quote(&new_system)
+    );
+    let started = Instant::now();
+    let (output, exit_status) = execute_command_on_channel(&sess, &diff_cmd, forward_agent)?;
+    result.record_command(&diff_cmd, &output, exit_status, started);
+    if exit_status != 0 {
+        let error_msg = format!("diff-closures failed with exit code: {}", exit_status);
+        return Ok(result.fail(error_msg, &progress_tx));
+    }
+
+    let diff = ClosureDiff::parse(&current_system, &new_system, &output);
+    for line in diff.detail_lines() {
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: hostname.to_string(),
+            phase: UpdatePhase::Planning,
+            output_line: Some(line),
+        });
+    }
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::Success {
+            reboot_required: Vec::new(),
+            rollback_to: None,
+        },
+        output_line: Some(format!("Plan: {}", diff.summary())),
+    });
+
+    result.plan = Some(diff);
+    result.success = true;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("+12 B"), Some(12));
+        assert_eq!(parse_size("-1.5 KiB"), Some(-1536));
+        assert_eq!(parse_size("+2.0 MiB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1.2 → 1.3"), None);
+        assert_eq!(parse_size("42"), None);
+    }
+
+    #[test]
+    fn sorts_diff_lines_by_kind() {
+        let output = "\
+foo: ∅ → 1.2, +1.0 MiB
+bar: 3.4 → ∅, -512.0 KiB
+baz: 1.0 → 1.1, +12.0 KiB
+qux: +4.0 KiB
+not a diff line
+";
+        let diff = ClosureDiff::parse("/nix/store/old", "/nix/store/new", output);
+
+        assert_eq!(diff.added, ["foo: ∅ → 1.2, +1.0 MiB"]);
+        assert_eq!(diff.removed, ["bar: 3.4 → ∅, -512.0 KiB"]);
+        assert_eq!(diff.changed, ["baz: 1.0 → 1.1, +12.0 KiB", "qux: +4.0 KiB"]);
+        assert_eq!(diff.size_delta, 1024 * 1024 - 512 * 1024 + 16 * 1024);
+        assert_eq!(diff.summary(), "1 added, 1 removed, 2 changed, +528.0 KiB");
+    }
+
+    #[test]
+    fn same_system_is_empty() {
+        let diff = ClosureDiff::parse("/nix/store/a", "/nix/store/a", "");
+        assert!(diff.is_empty());
+        assert_eq!(diff.summary(), "no changes");
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "+0.0 B");
+        assert_eq!(format_size(-2048), "-2.0 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "+3.0 GiB");
+    }
+}
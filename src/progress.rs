@@ -1,58 +1,208 @@
+use chrono::{DateTime, Utc};
 use ratatui::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::mem::discriminant;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::mpsc;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UpdatePhase {
-    Pending,
+    /// Waiting for its turn; `skipped` once the deployment stopped before
+    /// reaching the host
+    Pending {
+        skipped: bool,
+    },
     Connecting,
     RunningBeforeCommand,
     CheckingGit,
     PullingGit,
-    Rebuilding { progress: String },
+    Rebuilding {
+        progress: NixProgress,
+    },
+    Building {
+        progress: NixProgress,
+    },
+    CopyingClosure {
+        progress: String,
+    },
+    Planning,
+    Activating,
+    Confirming,
+    Rebooting,
+    WaitingForHost,
+    HealthCheck,
     RunningAfterCommand,
-    Success,
-    Failed { reason: String },
+    /// `reboot_required` lists what only takes effect after a reboot, e.g. `kernel`;
+    /// `rollback_to` is the generation the host had before a deployment that
+    /// created a new one
+    Success {
+        reboot_required: Vec<String>,
+        #[serde(default)]
+        rollback_to: Option<u64>,
+    },
+    Failed {
+        reason: String,
+    },
 }
 
-impl UpdatePhase {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for UpdatePhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            UpdatePhase::Pending => "Pending".to_string(),
-            UpdatePhase::Connecting => "Connecting...".to_string(),
-            UpdatePhase::RunningBeforeCommand => "Running before-command...".to_string(),
-            UpdatePhase::CheckingGit => "Checking git repo...".to_string(),
-            UpdatePhase::PullingGit => "Pulling git updates...".to_string(),
+            UpdatePhase::Pending { skipped: false } => write!(f, "Pending"),
+            UpdatePhase::Pending { skipped: true } => write!(f, "- Skipped"),
+            UpdatePhase::Connecting => write!(f, "Connecting..."),
+            UpdatePhase::RunningBeforeCommand => write!(f, "Running before-command..."),
+            UpdatePhase::CheckingGit => write!(f, "Checking git repo..."),
+            UpdatePhase::PullingGit => write!(f, "Pulling git updates..."),
             UpdatePhase::Rebuilding { progress } => {
                 if progress.is_empty() {
-                    "Rebuilding system...".to_string()
+                    write!(f, "Rebuilding system...")
+                } else {
+                    write!(f, "Rebuilding: {}", progress)
+                }
+            }
+            UpdatePhase::Building { progress } => {
+                if progress.is_empty() {
+                    write!(f, "Building locally...")
                 } else {
-                    format!("Rebuilding: {}", progress)
+                    write!(f, "Building locally: {}", progress)
                 }
             }
-            UpdatePhase::RunningAfterCommand => "Running after-command...".to_string(),
-            UpdatePhase::Success => "✓ Success".to_string(),
-            UpdatePhase::Failed { reason } => format!("✗ Failed: {}", reason),
+            UpdatePhase::CopyingClosure { progress } => {
+                if progress.is_empty() {
+                    write!(f, "Copying closure...")
+                } else {
+                    write!(f, "Copying closure: {}", progress)
+                }
+            }
+            UpdatePhase::Planning => write!(f, "Comparing closures..."),
+            UpdatePhase::Activating => write!(f, "Activating configuration..."),
+            UpdatePhase::Confirming => write!(f, "Confirming connectivity..."),
+            UpdatePhase::Rebooting => write!(f, "Rebooting..."),
+            UpdatePhase::WaitingForHost => write!(f, "Waiting for host..."),
+            UpdatePhase::HealthCheck => write!(f, "Running health checks..."),
+            UpdatePhase::RunningAfterCommand => write!(f, "Running after-command..."),
+            UpdatePhase::Success { .. } => write!(f, "✓ Success"),
+            UpdatePhase::Failed { reason } => write!(f, "✗ Failed: {}", reason),
         }
     }
+}
 
+impl UpdatePhase {
     pub fn color(&self) -> Color {
         match self {
-            UpdatePhase::Pending => Color::Gray,
+            UpdatePhase::Pending { skipped: false } => Color::Gray,
+            UpdatePhase::Pending { skipped: true } => Color::DarkGray,
             UpdatePhase::Connecting
             | UpdatePhase::RunningBeforeCommand
             | UpdatePhase::CheckingGit
             | UpdatePhase::PullingGit
             | UpdatePhase::Rebuilding { .. }
+            | UpdatePhase::Building { .. }
+            | UpdatePhase::CopyingClosure { .. }
+            | UpdatePhase::Planning
+            | UpdatePhase::Activating
+            | UpdatePhase::Confirming
+            | UpdatePhase::Rebooting
+            | UpdatePhase::WaitingForHost
+            | UpdatePhase::HealthCheck
             | UpdatePhase::RunningAfterCommand => Color::Yellow,
-            UpdatePhase::Success => Color::Green,
+            UpdatePhase::Success { .. } => Color::Green,
             UpdatePhase::Failed { .. } => Color::Red,
         }
     }
 
     pub fn is_terminal(&self) -> bool {
-        matches!(self, UpdatePhase::Success | UpdatePhase::Failed { .. })
+        matches!(
+            self,
+            UpdatePhase::Success { .. }
+                | UpdatePhase::Failed { .. }
+                | UpdatePhase::Pending { skipped: true }
+        )
+    }
+
+    /// Stable identifier used in reports
+    pub fn key(&self) -> &'static str {
+        match self {
+            UpdatePhase::Pending { .. } => "pending",
+            UpdatePhase::Connecting => "connecting",
+            UpdatePhase::RunningBeforeCommand => "before_command",
+            UpdatePhase::CheckingGit => "checking_git",
+            UpdatePhase::PullingGit => "pulling_git",
+            UpdatePhase::Rebuilding { .. } => "rebuilding",
+            UpdatePhase::Building { .. } => "building",
+            UpdatePhase::CopyingClosure { .. } => "copying_closure",
+            UpdatePhase::Planning => "planning",
+            UpdatePhase::Activating => "activating",
+            UpdatePhase::Confirming => "confirming",
+            UpdatePhase::Rebooting => "rebooting",
+            UpdatePhase::WaitingForHost => "waiting_for_host",
+            UpdatePhase::HealthCheck => "health_check",
+            UpdatePhase::RunningAfterCommand => "after_command",
+            UpdatePhase::Success { .. } => "success",
+            UpdatePhase::Failed { .. } => "failed",
+        }
+    }
+}
+
+/// When a phase was entered and how long the host stayed in it
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseTiming {
+    pub phase: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    #[serde(skip)]
+    started: Instant,
+}
+
+/// Counters from nix's structured log, see `NixLog`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NixProgress {
+    pub builds_done: u64,
+    pub builds_expected: u64,
+    /// Store paths copied from substituters
+    pub substitutions_done: u64,
+    pub substitutions_expected: u64,
+    pub bytes_downloaded: u64,
+}
+
+impl NixProgress {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Share of the builds and substitutions that are done, once any are known
+    pub fn fraction(&self) -> Option<f64> {
+        let expected = self.builds_expected + self.substitutions_expected;
+        let done = self.builds_done + self.substitutions_done;
+        (expected > 0).then(|| (done as f64 / expected as f64).min(1.0))
+    }
+}
+
+impl std::fmt::Display for NixProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if self.builds_expected > 0 {
+            parts.push(format!(
+                "{}/{} built",
+                self.builds_done, self.builds_expected
+            ));
+        }
+        if self.substitutions_expected > 0 {
+            parts.push(format!(
+                "{}/{} fetched",
+                self.substitutions_done, self.substitutions_expected
+            ));
+        }
+        if self.bytes_downloaded > 0 {
+            parts.push(format!(
+                "{:.1} MiB downloaded",
+                self.bytes_downloaded as f64 / (1024.0 * 1024.0)
+            ));
+        }
+        write!(f, "{}", parts.join(", "))
     }
 }
 
@@ -67,13 +217,47 @@ pub struct ProgressUpdate {
 pub struct ServerProgress {
     pub phase: UpdatePhase,
     pub full_output: String,
+    /// Phases reached so far, excluding `Pending` and the final outcome
+    pub phases: Vec<PhaseTiming>,
+    /// Components that changed but only take effect after a reboot
+    pub reboot_required: Vec<String>,
 }
 
 impl ServerProgress {
     pub fn new() -> Self {
         Self {
-            phase: UpdatePhase::Pending,
+            phase: UpdatePhase::Pending { skipped: false },
             full_output: String::new(),
+            phases: Vec::new(),
+            reboot_required: Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, update: ProgressUpdate) {
+        if discriminant(&self.phase) != discriminant(&update.phase) {
+            if let Some(last) = self.phases.last_mut() {
+                last.duration_secs = last.started.elapsed().as_secs_f64();
+            }
+            if !update.phase.is_terminal() {
+                self.phases.push(PhaseTiming {
+                    phase: update.phase.key(),
+                    started_at: Utc::now(),
+                    duration_secs: 0.0,
+                    started: Instant::now(),
+                });
+            }
+        }
+
+        if let UpdatePhase::Success {
+            reboot_required, ..
+        } = &update.phase
+        {
+            self.reboot_required = reboot_required.clone();
+        }
+        self.phase = update.phase;
+        if let Some(line) = update.output_line {
+            self.full_output.push_str(&line);
+            self.full_output.push('\n');
         }
     }
 }
@@ -89,57 +273,6 @@ pub fn create_progress_map(servers: &[String]) -> ProgressMap {
     Arc::new(Mutex::new(map))
 }
 
-pub fn parse_rebuild_progress(line: &str) -> Option<String> {
-    let line_lower = line.to_lowercase();
-
-    // Detect different phases of nixos-rebuild
-    if line_lower.contains("downloading") || line_lower.contains("download") {
-        // Try to extract package name
-        if let Some(start) = line.find('\'') {
-            if let Some(end) = line[start + 1..].find('\'') {
-                let pkg = &line[start + 1..start + 1 + end];
-                // Shorten long package names
-                if pkg.len() > 30 {
-                    return Some(format!("dl: {}...", &pkg[..27]));
-                }
-                return Some(format!("dl: {}", pkg));
-            }
-        }
-        return Some("downloading...".to_string());
-    }
-
-    if line_lower.contains("copying path") || line_lower.contains("copying") {
-        return Some("copying paths...".to_string());
-    }
-
-    if line_lower.contains("building") {
-        // Try to extract derivation info
-        if line.contains("derivation") {
-            if let Some(pos) = line.find(char::is_numeric) {
-                let num_str: String = line[pos..].chars().take_while(|c| c.is_numeric()).collect();
-                if !num_str.is_empty() {
-                    return Some(format!("building {} drv", num_str));
-                }
-            }
-        }
-        return Some("building...".to_string());
-    }
-
-    if line_lower.contains("activating") || line_lower.contains("activation") {
-        return Some("activating...".to_string());
-    }
-
-    if line_lower.contains("updating") && line_lower.contains("bootloader") {
-        return Some("updating bootloader...".to_string());
-    }
-
-    if line_lower.contains("reloading") {
-        return Some("reloading services...".to_string());
-    }
-
-    None
-}
-
 pub async fn progress_monitor_task(
     mut rx: mpsc::Receiver<ProgressUpdate>,
     progress_map: ProgressMap,
@@ -147,11 +280,7 @@ pub async fn progress_monitor_task(
     while let Some(update) = rx.recv().await {
         let mut map = progress_map.lock().unwrap();
         if let Some(server) = map.get_mut(&update.hostname) {
-            server.phase = update.phase;
-            if let Some(line) = update.output_line {
-                server.full_output.push_str(&line);
-                server.full_output.push('\n');
-            }
+            server.apply(update);
         }
     }
 }
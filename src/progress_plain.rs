@@ -0,0 +1,28 @@
+use std::mem::discriminant;
+use tokio::sync::mpsc;
+
+use crate::progress::{ProgressMap, ProgressUpdate};
+
+/// Line-oriented alternative to `progress_monitor_task` for non-interactive runs
+///
+/// Every output line is printed prefixed with its hostname, and each phase
+/// change gets a line of its own.
+pub async fn plain_progress_task(
+    mut rx: mpsc::Receiver<ProgressUpdate>,
+    progress_map: ProgressMap,
+) {
+    while let Some(update) = rx.recv().await {
+        let mut map = progress_map.lock().unwrap();
+        if let Some(server) = map.get_mut(&update.hostname) {
+            // Rebuilding carries a constantly changing progress string, only
+            // report when the phase itself changes
+            if discriminant(&server.phase) != discriminant(&update.phase) {
+                println!("[{}] == {}", update.hostname, update.phase);
+            }
+            if let Some(line) = &update.output_line {
+                println!("[{}] {}", update.hostname, line);
+            }
+            server.apply(update);
+        }
+    }
+}
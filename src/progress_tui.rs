@@ -6,7 +6,10 @@ use ratatui::{
 };
 use std::time::Duration;
 
-use crate::progress::ProgressMap;
+use crate::progress::{ProgressMap, UpdatePhase};
+
+/// Width of the build progress bar in the server list, in cells
+const PROGRESS_BAR_WIDTH: usize = 20;
 
 pub struct ProgressTui {
     server_list: Vec<String>,
@@ -18,6 +21,17 @@ pub struct ProgressTui {
     max_scroll: usize,
     server_list_area: Rect,
     output_area: Rect,
+    /// Whether each host has reached a terminal phase
+    finished: Vec<bool>,
+    /// Generation each host can be rolled back to, once its deployment
+    /// succeeded with a new generation
+    rollback_targets: Vec<Option<u64>>,
+    /// Host waiting for a second `r` to confirm its rollback
+    rollback_armed: Option<usize>,
+    rollback_requests: Vec<(String, u64)>,
+    /// Shown next to the server list title, e.g. for non-permanent actions
+    notice: Option<String>,
+    rollback_enabled: bool,
 }
 
 impl ProgressTui {
@@ -32,6 +46,61 @@ impl ProgressTui {
             max_scroll: 0,
             server_list_area: Rect::default(),
             output_area: Rect::default(),
+            finished: Vec::new(),
+            rollback_targets: Vec::new(),
+            rollback_armed: None,
+            rollback_requests: Vec::new(),
+            notice: None,
+            rollback_enabled: true,
+        }
+    }
+
+    pub fn with_notice(mut self, notice: String) -> Self {
+        self.notice = Some(notice);
+        self
+    }
+
+    /// Don't offer the rollback action, e.g. while planning
+    pub fn without_rollback(mut self) -> Self {
+        self.rollback_enabled = false;
+        self
+    }
+
+    fn hostname(&self, index: usize) -> Option<&str> {
+        self.server_list
+            .get(index)
+            .map(|server| server.split(':').next().unwrap_or(server))
+    }
+
+    /// Hosts the user asked to roll back since the last call, with the
+    /// generation to roll back to
+    pub fn take_rollback_requests(&mut self) -> Vec<(String, u64)> {
+        std::mem::take(&mut self.rollback_requests)
+    }
+
+    /// Generation the selected host can be rolled back to, if any
+    fn rollback_target(&self, index: usize) -> Option<u64> {
+        if !self.rollback_enabled {
+            return None;
+        }
+        self.rollback_targets.get(index).copied().flatten()
+    }
+
+    /// First `r` on a deployed host arms the rollback, the second one requests it
+    fn request_rollback(&mut self) {
+        let Some(generation) = self.rollback_target(self.selected_index) else {
+            self.rollback_armed = None;
+            return;
+        };
+
+        if self.rollback_armed == Some(self.selected_index) {
+            self.rollback_armed = None;
+            if let Some(hostname) = self.hostname(self.selected_index) {
+                self.rollback_requests
+                    .push((hostname.to_string(), generation));
+            }
+        } else {
+            self.rollback_armed = Some(self.selected_index);
         }
     }
 
@@ -109,7 +178,17 @@ impl ProgressTui {
                 let hostname = server.split(':').next().unwrap_or(server);
                 let status = map
                     .get(hostname)
-                    .map(|s| s.phase.to_string())
+                    .map(|s| {
+                        if s.reboot_required.is_empty() {
+                            s.phase.to_string()
+                        } else {
+                            format!(
+                                "{} [reboot required: {}]",
+                                s.phase,
+                                s.reboot_required.join(", ")
+                            )
+                        }
+                    })
                     .unwrap_or_else(|| "Unknown".to_string());
 
                 let color = map
@@ -118,17 +197,35 @@ impl ProgressTui {
                     .unwrap_or(Color::Gray);
 
                 let prefix = if i == self.selected_index { "> " } else { "  " };
-                let line = format!("{}{}: {}", prefix, hostname, status);
+                let mut line = Line::from(format!("{}{}: {}", prefix, hostname, status));
+                if let Some(fraction) = map.get(hostname).and_then(|s| nix_fraction(&s.phase)) {
+                    line.push_span(Span::raw(" "));
+                    line.push_span(progress_bar(fraction));
+                }
 
                 ListItem::new(line).style(Style::default().fg(color))
             })
             .collect();
 
-        let list = List::new(items).block(
-            Block::default()
-                .title("Server Status")
-                .borders(Borders::ALL),
-        );
+        let mut title = match &self.notice {
+            Some(notice) => format!("Server Status ({})", notice),
+            None => "Server Status".to_string(),
+        };
+        let armed = self
+            .rollback_armed
+            .and_then(|i| Some((self.hostname(i)?, self.rollback_target(i)?)));
+        match armed {
+            Some((hostname, generation)) => title.push_str(&format!(
+                " [Press r again to roll back {} to generation {}]",
+                hostname, generation
+            )),
+            None if self.rollback_target(self.selected_index).is_some() => {
+                title.push_str(" [r: roll back]")
+            }
+            None => {}
+        }
+
+        let list = List::new(items).block(Block::default().title(title).borders(Borders::ALL));
 
         frame.render_widget(list, area);
     }
@@ -185,12 +282,28 @@ impl ProgressTui {
 
     pub fn check_all_complete(&mut self, progress_map: &ProgressMap) -> bool {
         let map = progress_map.lock().unwrap();
-        let all_done = self.server_list.iter().all(|server| {
-            let hostname = server.split(':').next().unwrap_or(server);
-            map.get(hostname)
-                .map(|s| s.phase.is_terminal())
-                .unwrap_or(false)
-        });
+        self.finished = self
+            .server_list
+            .iter()
+            .map(|server| {
+                let hostname = server.split(':').next().unwrap_or(server);
+                map.get(hostname)
+                    .map(|s| s.phase.is_terminal())
+                    .unwrap_or(false)
+            })
+            .collect();
+        self.rollback_targets = self
+            .server_list
+            .iter()
+            .map(|server| {
+                let hostname = server.split(':').next().unwrap_or(server);
+                match map.get(hostname).map(|s| &s.phase) {
+                    Some(UpdatePhase::Success { rollback_to, .. }) => *rollback_to,
+                    _ => None,
+                }
+            })
+            .collect();
+        let all_done = self.finished.iter().all(|&done| done);
         self.all_complete = all_done;
         all_done
     }
@@ -200,48 +313,52 @@ impl ProgressTui {
         // This is the only delay in the main loop, so keep it minimal
         if event::poll(Duration::from_millis(10))? {
             match event::read()? {
-                Event::Key(key) => {
-                    if key.kind == KeyEventKind::Press {
-                        match key.code {
-                            KeyCode::Up => {
-                                self.previous();
-                                self.ctrl_c_count = 0; // Reset on other key
-                                return Ok(false);
-                            }
-                            KeyCode::Down => {
-                                self.next();
-                                self.ctrl_c_count = 0; // Reset on other key
-                                return Ok(false);
-                            }
-                            KeyCode::PageUp => {
-                                self.scroll_up();
-                                self.ctrl_c_count = 0;
-                                return Ok(false);
-                            }
-                            KeyCode::PageDown => {
-                                self.scroll_down();
-                                self.ctrl_c_count = 0;
-                                return Ok(false);
-                            }
-                            KeyCode::Char('c')
-                                if key
-                                    .modifiers
-                                    .contains(crossterm::event::KeyModifiers::CONTROL) =>
-                            {
-                                self.ctrl_c_count += 1;
-                                // If all complete, quit immediately
-                                // Otherwise require 2 presses to force quit
-                                if self.all_complete || self.ctrl_c_count >= 2 {
-                                    return Ok(true); // Signal to quit
-                                }
-                                return Ok(false);
-                            }
-                            KeyCode::Char('q') if self.all_complete => {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    match key.code {
+                        KeyCode::Up => {
+                            self.previous();
+                            self.ctrl_c_count = 0; // Reset on other key
+                            return Ok(false);
+                        }
+                        KeyCode::Down => {
+                            self.next();
+                            self.ctrl_c_count = 0; // Reset on other key
+                            return Ok(false);
+                        }
+                        KeyCode::PageUp => {
+                            self.scroll_up();
+                            self.ctrl_c_count = 0;
+                            return Ok(false);
+                        }
+                        KeyCode::PageDown => {
+                            self.scroll_down();
+                            self.ctrl_c_count = 0;
+                            return Ok(false);
+                        }
+                        KeyCode::Char('c')
+                            if key
+                                .modifiers
+                                .contains(crossterm::event::KeyModifiers::CONTROL) =>
+                        {
+                            self.ctrl_c_count += 1;
+                            // If all complete, quit immediately
+                            // Otherwise require 2 presses to force quit
+                            if self.all_complete || self.ctrl_c_count >= 2 {
                                 return Ok(true); // Signal to quit
                             }
-                            _ => {
-                                self.ctrl_c_count = 0; // Reset on other key
-                            }
+                            return Ok(false);
+                        }
+                        KeyCode::Char('q') if self.all_complete => {
+                            return Ok(true); // Signal to quit
+                        }
+                        KeyCode::Char('r') => {
+                            self.request_rollback();
+                            self.ctrl_c_count = 0;
+                            return Ok(false);
+                        }
+                        _ => {
+                            self.ctrl_c_count = 0; // Reset on other key
+                            self.rollback_armed = None;
                         }
                     }
                 }
@@ -257,45 +374,26 @@ impl ProgressTui {
                     }
 
                     self.ctrl_c_count = 0; // Reset on mouse events
+                    let position = Position::new(mouse.column, mouse.row);
                     match mouse.kind {
-                        MouseEventKind::Down(MouseButton::Left) => {
-                            // Check if click is in server list area
-                            if mouse.column >= self.server_list_area.x
-                                && mouse.column
-                                    < self.server_list_area.x + self.server_list_area.width
-                                && mouse.row >= self.server_list_area.y
-                                && mouse.row
-                                    < self.server_list_area.y + self.server_list_area.height
-                            {
-                                // Calculate which server was clicked (accounting for border)
-                                let relative_y =
-                                    mouse.row.saturating_sub(self.server_list_area.y + 1);
-                                if relative_y < self.server_list.len() as u16 {
-                                    self.selected_index = relative_y as usize;
-                                    self.scroll_offset = 0;
-                                    self.auto_scroll = true;
-                                }
+                        // Click in server list area selects the server
+                        MouseEventKind::Down(MouseButton::Left)
+                            if self.server_list_area.contains(position) =>
+                        {
+                            // Calculate which server was clicked (accounting for border)
+                            let relative_y = mouse.row.saturating_sub(self.server_list_area.y + 1);
+                            if relative_y < self.server_list.len() as u16 {
+                                self.selected_index = relative_y as usize;
+                                self.scroll_offset = 0;
+                                self.auto_scroll = true;
                             }
                         }
-                        MouseEventKind::ScrollUp => {
-                            // Check if mouse is over output area for scrolling
-                            if mouse.column >= self.output_area.x
-                                && mouse.column < self.output_area.x + self.output_area.width
-                                && mouse.row >= self.output_area.y
-                                && mouse.row < self.output_area.y + self.output_area.height
-                            {
-                                self.scroll_up();
-                            }
+                        // Scrolling only applies while over the output area
+                        MouseEventKind::ScrollUp if self.output_area.contains(position) => {
+                            self.scroll_up();
                         }
-                        MouseEventKind::ScrollDown => {
-                            // Check if mouse is over output area for scrolling
-                            if mouse.column >= self.output_area.x
-                                && mouse.column < self.output_area.x + self.output_area.width
-                                && mouse.row >= self.output_area.y
-                                && mouse.row < self.output_area.y + self.output_area.height
-                            {
-                                self.scroll_down();
-                            }
+                        MouseEventKind::ScrollDown if self.output_area.contains(position) => {
+                            self.scroll_down();
                         }
                         _ => {}
                     }
@@ -306,3 +404,26 @@ impl ProgressTui {
         Ok(false) // Continue running
     }
 }
+
+/// How far nix got while the host is building, if nix reported any numbers
+fn nix_fraction(phase: &UpdatePhase) -> Option<f64> {
+    match phase {
+        UpdatePhase::Rebuilding { progress } | UpdatePhase::Building { progress } => {
+            progress.fraction()
+        }
+        _ => None,
+    }
+}
+
+fn progress_bar(fraction: f64) -> Span<'static> {
+    let filled = (fraction * PROGRESS_BAR_WIDTH as f64).round() as usize;
+    Span::styled(
+        format!(
+            "[{}{}] {:>3.0}%",
+            "█".repeat(filled),
+            "░".repeat(PROGRESS_BAR_WIDTH - filled),
+            fraction * 100.0
+        ),
+        Style::default().fg(Color::Cyan),
+    )
+}
@@ -0,0 +1,155 @@
+use anyhow::Result;
+use ssh2::{ErrorCode, Session};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::host::Host;
+use crate::progress::{ProgressUpdate, UpdatePhase};
+use crate::ssh_executor::{Sudo, execute_command_on_channel, execute_privileged};
+use crate::updater::{ConnectOptions, UpdateResult, connect_session};
+
+fn read_trimmed(sess: &Session, command: &str, forward_agent: bool) -> Result<String> {
+    let (output, exit_status) = execute_command_on_channel(sess, command, forward_agent)?;
+    if exit_status != 0 {
+        anyhow::bail!("{} failed with exit code: {}", command, exit_status);
+    }
+    Ok(output.trim().to_string())
+}
+
+// libssh2 error codes of a connection that went away
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_CHANNEL_CLOSED: i32 = -26;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+
+/// Whether `error` is the connection going away, as expected once the host
+/// shuts down
+fn is_disconnect(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<ssh2::Error>() {
+        return matches!(
+            error.code(),
+            ErrorCode::Session(
+                LIBSSH2_ERROR_SOCKET_SEND
+                    | LIBSSH2_ERROR_SOCKET_DISCONNECT
+                    | LIBSSH2_ERROR_CHANNEL_CLOSED
+                    | LIBSSH2_ERROR_SOCKET_RECV
+            )
+        );
+    }
+    error.downcast_ref::<std::io::Error>().is_some_and(|error| {
+        matches!(
+            error.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+        )
+    })
+}
+
+/// Reboot into the generation the system profile points to and reconnect
+///
+/// Returns the new session, or the failure reason if the host didn't come back
+/// within `timeout` or booted something else.
+#[allow(clippy::too_many_arguments)]
+pub fn reboot_and_wait(
+    sess: &Session,
+    host: &Host,
+    connect: &ConnectOptions,
+    forward_agent: bool,
+    sudo: &Sudo,
+    timeout: Duration,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<std::result::Result<Session, String>> {
+    let hostname = host.name.as_str();
+
+    let expected = read_trimmed(
+        sess,
+        "readlink -f /nix/var/nix/profiles/system",
+        forward_agent,
+    )?;
+    let boot_id = read_trimmed(sess, "cat /proc/sys/kernel/random/boot_id", forward_agent)?;
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::Rebooting,
+        output_line: Some(format!("Rebooting into {}...", expected)),
+    });
+
+    // Detach the reboot so the command returns before sshd goes away
+    let reboot_cmd = "nohup sh -c 'sleep 1; systemctl reboot' > /dev/null 2>&1 &";
+    let started = Instant::now();
+    let exit_status = match execute_privileged(sess, reboot_cmd, forward_agent, sudo) {
+        Ok((_, exit_status)) => exit_status,
+        // The host went down before the command returned
+        Err(e) if is_disconnect(&e) => 0,
+        Err(e) => return Ok(Err(format!("Reboot failed: {}", e))),
+    };
+    result.record_command(reboot_cmd, "", exit_status, started);
+    if exit_status != 0 {
+        return Ok(Err(format!(
+            "Reboot failed with exit code: {}",
+            exit_status
+        )));
+    }
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::WaitingForHost,
+        output_line: Some(format!(
+            "Waiting up to {}s for the host to come back...",
+            timeout.as_secs()
+        )),
+    });
+
+    // Connection attempts report Connecting/Failed phases; keep those out of
+    // the TUI while waiting
+    let (quiet_tx, _) = mpsc::channel(1);
+    let deadline = Instant::now() + timeout;
+
+    let new_sess = loop {
+        std::thread::sleep(Duration::from_secs(5));
+
+        if let Ok(Ok(new_sess)) = connect_session(host, connect, Duration::from_secs(10), &quiet_tx)
+        {
+            // Still the old boot if the reboot hasn't started yet
+            match read_trimmed(
+                &new_sess,
+                "cat /proc/sys/kernel/random/boot_id",
+                forward_agent,
+            ) {
+                Ok(new_boot_id) if new_boot_id != boot_id => break new_sess,
+                _ => {}
+            }
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(Err(format!(
+                "Host did not come back within {}s after rebooting",
+                timeout.as_secs()
+            )));
+        }
+    };
+
+    let current = read_trimmed(&new_sess, "readlink -f /run/current-system", forward_agent)?;
+    if current != expected {
+        return Ok(Err(format!(
+            "Host booted {} instead of {}",
+            current, expected
+        )));
+    }
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::WaitingForHost,
+        output_line: Some(format!(
+            "✓ Host is back after {}s running {}",
+            started.elapsed().as_secs(),
+            current
+        )),
+    });
+
+    Ok(Ok(new_sess))
+}
@@ -0,0 +1,244 @@
+use regex::Regex;
+use serde::Serialize;
+use std::sync::LazyLock;
+
+use crate::nix_log::{NixLog, derivation_name};
+
+static FAILED_BUILD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?:builder for|Cannot build) '(/nix/store/[^']+\.drv)'").expect("valid regex")
+});
+static FAILED_UNITS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"the following units failed: (.+)$").expect("valid regex"));
+static SOURCE_PREFIX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"/nix/store/[a-z0-9]{32}-source/").expect("valid regex"));
+
+/// Trace lines kept for an evaluation error, counted from its end
+const MAX_TRACE_LINES: usize = 20;
+
+/// The part of a failed command's output that explains the failure
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractedError {
+    /// One line, shown as the failure reason
+    pub summary: String,
+    /// Trace, log hints or failed units, one entry per line
+    pub details: Vec<String>,
+}
+
+/// Find why a nix build, `nixos-rebuild` or an activation failed
+///
+/// Failed derivations and units win over `error:` lines, which a failed
+/// build prints as well and activation scripts may print in passing.
+pub fn extract_error(output: &str) -> Option<ExtractedError> {
+    let lines = readable_lines(output);
+    failed_builds(&lines)
+        .or_else(|| failed_units(&lines))
+        .or_else(|| evaluation_error(&lines))
+}
+
+/// Output lines as nix would have printed them, without colors
+fn readable_lines(output: &str) -> Vec<String> {
+    let mut nix_log = NixLog::default();
+    output
+        .lines()
+        .filter_map(|line| {
+            let stripped = strip_ansi_escapes::strip(line.trim_end_matches('\r').as_bytes());
+            nix_log.parse(&String::from_utf8_lossy(&stripped))
+        })
+        .flat_map(|text| text.lines().map(str::to_string).collect::<Vec<_>>())
+        .collect()
+}
+
+fn failed_builds(lines: &[String]) -> Option<ExtractedError> {
+    let mut derivations: Vec<&str> = Vec::new();
+    for captures in lines.iter().filter_map(|line| FAILED_BUILD.captures(line)) {
+        let derivation = captures.get(1).map_or("", |m| m.as_str());
+        if !derivations.contains(&derivation) {
+            derivations.push(derivation);
+        }
+    }
+    if derivations.is_empty() {
+        return None;
+    }
+
+    let names: Vec<String> = derivations.iter().map(|d| derivation_name(d)).collect();
+    let summary = match names.as_slice() {
+        [name] => format!("build of {} failed", name),
+        names => format!("builds of {} failed", names.join(", ")),
+    };
+    let details = derivations
+        .iter()
+        .zip(&names)
+        .map(|(derivation, name)| format!("{}: nix log {}", name, derivation))
+        .collect();
+
+    Some(ExtractedError { summary, details })
+}
+
+/// The first `error:` block, reduced to its trace and innermost message
+///
+/// Nix indents the lines that belong to an error, so the block ends at the
+/// next line that isn't indented.
+fn evaluation_error(lines: &[String]) -> Option<ExtractedError> {
+    let start = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("error:"))?;
+    let block: Vec<&str> = std::iter::once(lines[start].as_str())
+        .chain(
+            lines[start + 1..]
+                .iter()
+                .take_while(|line| line.is_empty() || line.starts_with(char::is_whitespace))
+                .map(String::as_str),
+        )
+        .map(str::trim)
+        .collect();
+
+    // Newer nix prints the trace first and the actual error last
+    let message_index = block
+        .iter()
+        .rposition(|line| line.starts_with("error:") && line.len() > "error:".len())?;
+    let message = block[message_index]["error:".len()..].trim();
+    let location = block[message_index..]
+        .iter()
+        .chain(block[..message_index].iter().rev())
+        .find_map(|line| line.strip_prefix("at "))
+        .map(|at| {
+            SOURCE_PREFIX
+                .replace(at.trim_end_matches(':'), "")
+                .to_string()
+        });
+
+    let summary = match &location {
+        Some(location) => format!("{} (at {})", message, location),
+        None => message.to_string(),
+    };
+    let mut details: Vec<String> = block
+        .iter()
+        .filter(|line| {
+            line.starts_with('…') || line.starts_with("at ") || line.starts_with("error:")
+        })
+        .filter(|line| **line != "error:")
+        .map(|line| SOURCE_PREFIX.replace_all(line, "").to_string())
+        .collect();
+    if details.len() > MAX_TRACE_LINES {
+        details.drain(..details.len() - MAX_TRACE_LINES);
+    }
+
+    Some(ExtractedError { summary, details })
+}
+
+fn failed_units(lines: &[String]) -> Option<ExtractedError> {
+    let mut units: Vec<String> = Vec::new();
+    for captures in lines.iter().filter_map(|line| FAILED_UNITS.captures(line)) {
+        for unit in captures[1].split([' ', ',']).filter(|u| !u.is_empty()) {
+            if !units.iter().any(|u| u == unit) {
+                units.push(unit.to_string());
+            }
+        }
+    }
+    if units.is_empty() {
+        return None;
+    }
+
+    Some(ExtractedError {
+        summary: format!("failed units: {}", units.join(", ")),
+        details: units
+            .iter()
+            .map(|unit| format!("{}: journalctl -u {}", unit, unit))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_builds_win_over_error_lines() {
+        let output = "\
+error: builder for '/nix/store/0123abcd-hello-2.12.drv' failed with exit code 2;
+       last 10 log lines:
+       > make: *** [Makefile:1: all] Error 1
+error: 1 dependencies of derivation '/nix/store/4567efgh-nixos-system-web.drv' failed to build
+error: Cannot build '/nix/store/89abijkl-other-1.0.drv'.
+error: builder for '/nix/store/0123abcd-hello-2.12.drv' failed with exit code 2;
+";
+        let error = extract_error(output).unwrap();
+        assert_eq!(error.summary, "builds of hello-2.12, other-1.0 failed");
+        assert_eq!(
+            error.details,
+            [
+                "hello-2.12: nix log /nix/store/0123abcd-hello-2.12.drv",
+                "other-1.0: nix log /nix/store/89abijkl-other-1.0.drv",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_units() {
+        let output = "\
+activating the configuration...
+error: something in passing
+warning: the following units failed: nginx.service, acme-web.service
+warning: the following units failed: nginx.service
+";
+        let error = extract_error(output).unwrap();
+        assert_eq!(
+            error.summary,
+            "failed units: nginx.service, acme-web.service"
+        );
+        assert_eq!(
+            error.details,
+            [
+                "nginx.service: journalctl -u nginx.service",
+                "acme-web.service: journalctl -u acme-web.service",
+            ]
+        );
+    }
+
+    #[test]
+    fn evaluation_error_with_trace() {
+        let output = "\
+building the system configuration...
+error:
+       … while evaluating the attribute 'config.system.build.toplevel'
+         at /nix/store/0123456789abcdfghijklmnpqrsvwxyz-source/lib/modules.nix:1:1:
+
+       … while calling the 'throw' builtin
+         at /nix/store/0123456789abcdfghijklmnpqrsvwxyz-source/hosts/web.nix:12:5:
+
+       error: \u{1b}[1mservices.foo\u{1b}[0m does not exist
+Command 'nix' returned non-zero exit status 1.
+";
+        let error = extract_error(output).unwrap();
+        assert_eq!(
+            error.summary,
+            "services.foo does not exist (at hosts/web.nix:12:5)"
+        );
+        assert_eq!(
+            error.details,
+            [
+                "… while evaluating the attribute 'config.system.build.toplevel'",
+                "at lib/modules.nix:1:1:",
+                "… while calling the 'throw' builtin",
+                "at hosts/web.nix:12:5:",
+                "error: services.foo does not exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_structured_log() {
+        let output = r#"@nix {"action":"msg","level":0,"msg":"error: attribute 'web' missing"}
+"#;
+        assert_eq!(
+            extract_error(output).unwrap().summary,
+            "attribute 'web' missing"
+        );
+    }
+
+    #[test]
+    fn nothing_to_extract() {
+        assert!(extract_error("").is_none());
+        assert!(extract_error("building...\nerror:\n").is_none());
+    }
+}
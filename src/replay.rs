@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc as std_mpsc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::progress::{ProgressUpdate, UpdatePhase};
+
+/// A `ProgressUpdate` as stored in `events.jsonl`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// Seconds since the recording started
+    pub at_secs: f64,
+    pub hostname: String,
+    pub phase: UpdatePhase,
+    pub output_line: Option<String>,
+}
+
+impl From<RecordedEvent> for ProgressUpdate {
+    fn from(event: RecordedEvent) -> Self {
+        ProgressUpdate {
+            hostname: event.hostname,
+            phase: event.phase,
+            output_line: event.output_line,
+        }
+    }
+}
+
+/// Writes the progress updates of a run to a file as they pass by
+pub struct EventRecorder {
+    file: BufWriter<File>,
+}
+
+impl EventRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// Record every update from `rx` and pass it on to the returned receiver
+    ///
+    /// The events are written on a thread of their own, so a slow disk never
+    /// holds up the progress channel. The returned receiver closes once `rx`
+    /// does and the recording is complete, so the progress monitor finishes
+    /// as usual.
+    pub fn tap(
+        self,
+        rt: &Runtime,
+        mut rx: mpsc::Receiver<ProgressUpdate>,
+    ) -> mpsc::Receiver<ProgressUpdate> {
+        let (tx, out) = mpsc::channel(1000);
+        let (events_tx, events_rx) = std_mpsc::channel();
+        let writer = std::thread::spawn(move || self.write_events(events_rx));
+        let started = Instant::now();
+
+        rt.spawn(async move {
+            while let Some(update) = rx.recv().await {
+                // Fails once the writer gave up, which must not hold up the
+                // deployment either
+                let _ = events_tx.send(RecordedEvent {
+                    at_secs: started.elapsed().as_secs_f64(),
+                    hostname: update.hostname.clone(),
+                    phase: update.phase.clone(),
+                    output_line: update.output_line.clone(),
+                });
+                if tx.send(update).await.is_err() {
+                    break;
+                }
+            }
+            drop(events_tx);
+            let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+        });
+
+        out
+    }
+
+    fn write_events(mut self, events: std_mpsc::Receiver<RecordedEvent>) {
+        while let Ok(event) = events.recv() {
+            // Write whatever queued up meanwhile before flushing
+            for event in std::iter::once(event).chain(events.try_iter()) {
+                let written = serde_json::to_string(&event)
+                    .ok()
+                    .is_some_and(|line| writeln!(self.file, "{}", line).is_ok());
+                if !written {
+                    return;
+                }
+            }
+            if self.file.flush().is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Read the events of a recorded run
+pub fn load_events(path: &Path) -> Result<Vec<RecordedEvent>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // A run that was killed may end in a partial line
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Send recorded events into a progress channel
+///
+/// With `real_time`, the events keep the spacing they were recorded with;
+/// otherwise they are sent as fast as the channel takes them.
+pub async fn replay(
+    events: Vec<RecordedEvent>,
+    progress_tx: mpsc::Sender<ProgressUpdate>,
+    real_time: bool,
+) {
+    let started = Instant::now();
+    for event in events {
+        if real_time {
+            tokio::time::sleep_until(started + Duration::from_secs_f64(event.at_secs.max(0.0)))
+                .await;
+        }
+        if progress_tx.send(event.into()).await.is_err() {
+            break;
+        }
+    }
+}
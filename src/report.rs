@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::Path;
+
+use crate::progress::{PhaseTiming, ProgressMap, UpdatePhase};
+use crate::updater::UpdateResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    Junit,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeploymentReport {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub hosts: Vec<HostReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostReport {
+    #[serde(flatten)]
+    pub result: UpdateResult,
+    pub failure_reason: Option<String>,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl DeploymentReport {
+    /// Combine the update results with the phase history of the progress map
+    pub fn new(
+        started_at: DateTime<Utc>,
+        results: &[UpdateResult],
+        progress_map: &ProgressMap,
+    ) -> Self {
+        let finished_at = Utc::now();
+        let map = progress_map.lock().unwrap();
+
+        let hosts = results
+            .iter()
+            .map(|result| {
+                let progress = map.get(&result.hostname);
+                let failure_reason = match progress.map(|p| &p.phase) {
+                    Some(UpdatePhase::Failed { reason }) => Some(reason.clone()),
+                    _ if !result.success && !result.skipped => Some("Update failed".to_string()),
+                    _ => None,
+                };
+
+                HostReport {
+                    result: result.clone(),
+                    failure_reason,
+                    phases: progress.map(|p| p.phases.clone()).unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        Self {
+            started_at,
+            finished_at,
+            duration_secs: (finished_at - started_at).as_seconds_f64(),
+            hosts,
+        }
+    }
+
+    pub fn write(&self, format: ReportFormat, path: &Path) -> Result<()> {
+        let contents = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+            ReportFormat::Junit => self.to_junit(),
+        };
+
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed to write report to {}", path.display()))
+    }
+
+    /// One test suite for the run with a test case per host
+    fn to_junit(&self) -> String {
+        let failures = self
+            .hosts
+            .iter()
+            .filter(|h| !h.result.success && !h.result.skipped)
+            .count();
+        let skipped = self.hosts.iter().filter(|h| h.result.skipped).count();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<testsuites name=\"nix-deploy\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+            self.hosts.len(),
+            failures,
+            skipped,
+            self.duration_secs
+        ));
+        xml.push_str(&format!(
+            "  <testsuite name=\"nix-deploy\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\" time=\"{:.3}\" timestamp=\"{}\">\n",
+            self.hosts.len(),
+            failures,
+            skipped,
+            self.duration_secs,
+            self.started_at.format("%Y-%m-%dT%H:%M:%S")
+        ));
+
+        for host in &self.hosts {
+            let time: f64 = host.phases.iter().map(|p| p.duration_secs).sum();
+            xml.push_str(&format!(
+                "    <testcase classname=\"nix-deploy\" name=\"{}\" time=\"{:.3}\">\n",
+                xml_escape(&host.result.hostname),
+                time
+            ));
+
+            xml.push_str("      <properties>\n");
+            let mut properties: Vec<(String, String)> = Vec::new();
+            if let Some(rev) = &host.result.git_rev_before {
+                properties.push(("git_rev_before".to_string(), rev.clone()));
+            }
+            if let Some(rev) = &host.result.git_rev_after {
+                properties.push(("git_rev_after".to_string(), rev.clone()));
+            }
+            if let Some(generation) = host.result.generation {
+                properties.push(("generation".to_string(), generation.to_string()));
+            }
+            if !host.result.reboot_required.is_empty() {
+                properties.push((
+                    "reboot_required".to_string(),
+                    host.result.reboot_required.join(","),
+                ));
+            }
+            for phase in &host.phases {
+                properties.push((
+                    format!("phase.{}", phase.phase),
+                    format!("{:.3}", phase.duration_secs),
+                ));
+            }
+            for command in &host.result.commands {
+                properties.push((
+                    format!("exit_code: {}", command.command),
+                    command.exit_code.to_string(),
+                ));
+            }
+            for (name, value) in properties {
+                xml.push_str(&format!(
+                    "        <property name=\"{}\" value=\"{}\"/>\n",
+                    xml_escape(&name),
+                    xml_escape(&value)
+                ));
+            }
+            xml.push_str("      </properties>\n");
+
+            if host.result.skipped {
+                xml.push_str(&format!(
+                    "      <skipped message=\"{}\"/>\n",
+                    xml_escape(host.result.output.trim())
+                ));
+            }
+            if let Some(reason) = &host.failure_reason {
+                xml.push_str(&format!(
+                    "      <failure message=\"{}\"/>\n",
+                    xml_escape(reason)
+                ));
+            }
+
+            xml.push_str(&format!(
+                "      <system-out>{}</system-out>\n",
+                xml_escape(&host.result.output)
+            ));
+            xml.push_str("    </testcase>\n");
+        }
+
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+/// Escape text for XML, dropping ANSI sequences and control characters that
+/// XML 1.0 does not allow
+fn xml_escape(text: &str) -> String {
+    let stripped = strip_ansi_escapes::strip(text.as_bytes());
+    let text = String::from_utf8_lossy(&stripped);
+
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
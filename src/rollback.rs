@@ -0,0 +1,117 @@
+use anyhow::Result;
+use ssh2::Session;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::action::RebuildAction;
+use crate::host::Host;
+use crate::progress::{ProgressUpdate, UpdatePhase};
+use crate::ssh_executor::{Sudo, execute_command_on_channel};
+use crate::updater::{
+    UpdateOptions, UpdateResult, connect_session, query_system_generation, switch_to_generation,
+};
+
+/// Generation numbers of the system profile, oldest first
+fn list_generations(sess: &Session, forward_agent: bool) -> Result<Vec<u64>> {
+    let (output, exit_status) = execute_command_on_channel(
+        sess,
+        "nix-env -p /nix/var/nix/profiles/system --list-generations",
+        forward_agent,
+    )?;
+    if exit_status != 0 {
+        anyhow::bail!("Listing generations failed: {}", output.trim());
+    }
+
+    // "  42   2024-05-01 12:00:00   (current)"
+    Ok(output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next()?.parse().ok())
+        .collect())
+}
+
+/// Switch the host back to `generation`, or to the one before the current
+/// generation if `None`
+pub fn rollback_server_blocking(
+    host: &Host,
+    generation: Option<u64>,
+    options: &UpdateOptions,
+    progress_tx: mpsc::Sender<ProgressUpdate>,
+) -> Result<UpdateResult> {
+    let hostname = host.name.as_str();
+    let forward_agent = options.forward_agent;
+    let mut result = UpdateResult::new(hostname);
+
+    let sess = match connect_session(
+        host,
+        &options.connect,
+        Duration::from_secs(60),
+        &progress_tx,
+    )? {
+        Ok(sess) => sess,
+        Err(error_msg) => return Ok(result.fail(error_msg, &progress_tx)),
+    };
+
+    let Some(current) = query_system_generation(&sess, forward_agent) else {
+        let error_msg = "Cannot determine the current generation".to_string();
+        return Ok(result.fail(error_msg, &progress_tx));
+    };
+    let generations = list_generations(&sess, forward_agent)?;
+
+    let target = match generation {
+        Some(generation) if generations.contains(&generation) => generation,
+        Some(generation) => {
+            let error_msg = format!("Generation {} does not exist", generation);
+            return Ok(result.fail(error_msg, &progress_tx));
+        }
+        None => match generations.iter().rev().find(|&&g| g < current) {
+            Some(&previous) => previous,
+            None => {
+                let error_msg = format!("No generation before {} to roll back to", current);
+                return Ok(result.fail(error_msg, &progress_tx));
+            }
+        },
+    };
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::Activating,
+        output_line: Some(format!(
+            "Current generation is {}, switching to {}",
+            current, target
+        )),
+    });
+
+    let exit_status = switch_to_generation(
+        &sess,
+        target,
+        options.action == RebuildAction::Boot,
+        forward_agent,
+        &Sudo::for_host(host, options.sudo_password.as_deref()),
+        &progress_tx,
+        &mut result,
+    )?;
+    if exit_status != 0 {
+        let error_msg = format!(
+            "Switching to generation {} failed with exit code: {}",
+            target, exit_status
+        );
+        return Ok(result.fail(error_msg, &progress_tx));
+    }
+
+    result.generation = query_system_generation(&sess, forward_agent);
+    let active = result
+        .generation
+        .map_or_else(|| "unknown".to_string(), |g| g.to_string());
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase: UpdatePhase::Success {
+            reboot_required: Vec::new(),
+            rollback_to: None,
+        },
+        output_line: Some(format!("✓ Generation {} is now active", active)),
+    });
+
+    result.success = true;
+    Ok(result)
+}
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::paths::state_dir;
+use crate::report::{DeploymentReport, ReportFormat};
+use crate::updater::{Operation, UpdateResult};
+
+/// How a run was started, written to `run.json` before any host is touched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunParameters {
+    pub started_at: DateTime<Utc>,
+    pub version: String,
+    pub command_line: Vec<String>,
+    /// Local user who started the run
+    pub user: Option<String>,
+    pub operation: Operation,
+    pub action: String,
+    pub hosts: Vec<String>,
+    pub local_build: bool,
+}
+
+/// Directory with everything recorded about one run
+///
+/// ```text
+/// $XDG_STATE_HOME/nix-deploy/runs/2025-01-31T14-05-09Z/
+///   run.json          parameters of the run
+///   summary.json      the JSON deployment report
+///   events.jsonl      progress updates with their timing, for `replay`
+///   <host>.log        output of every command, without ANSI escape codes
+///   <host>.raw.log    the same output as received
+/// ```
+#[derive(Debug, Clone)]
+pub struct RunLog {
+    dir: PathBuf,
+}
+
+impl RunLog {
+    /// `$XDG_STATE_HOME/nix-deploy/runs`
+    pub fn runs_dir() -> Result<PathBuf> {
+        Ok(state_dir()?.join("runs"))
+    }
+
+    /// Create the directory of a run and write its parameters
+    pub fn create(parameters: &RunParameters) -> Result<Self> {
+        let runs_dir = Self::runs_dir()?;
+        std::fs::create_dir_all(&runs_dir)
+            .with_context(|| format!("Failed to create {}", runs_dir.display()))?;
+
+        // Runs started within the same second get a suffix
+        let name = parameters
+            .started_at
+            .format("%Y-%m-%dT%H-%M-%SZ")
+            .to_string();
+        let mut dir = runs_dir.join(&name);
+        let mut suffix = 1;
+        while dir.exists() {
+            suffix += 1;
+            dir = runs_dir.join(format!("{}-{}", name, suffix));
+        }
+        std::fs::create_dir(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let run_log = Self { dir };
+        run_log.write("run.json", &serde_json::to_string_pretty(parameters)?)?;
+        Ok(run_log)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn events_path(&self) -> PathBuf {
+        self.dir.join("events.jsonl")
+    }
+
+    /// Directory of a recorded run: a path, the name of a directory in
+    /// `runs_dir`, or the latest run if `run` is `None`
+    pub fn find(run: Option<&str>) -> Result<PathBuf> {
+        let runs_dir = Self::runs_dir()?;
+        match run {
+            Some(run) if Path::new(run).is_dir() => Ok(PathBuf::from(run)),
+            Some(run) => {
+                let dir = runs_dir.join(run);
+                if dir.is_dir() {
+                    Ok(dir)
+                } else {
+                    anyhow::bail!("No recorded run {} in {}", run, runs_dir.display())
+                }
+            }
+            None => {
+                // The names sort by start time
+                let mut runs: Vec<PathBuf> = std::fs::read_dir(&runs_dir)
+                    .with_context(|| format!("Failed to read {}", runs_dir.display()))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.is_dir())
+                    .collect();
+                runs.sort();
+                runs.pop()
+                    .with_context(|| format!("No recorded runs in {}", runs_dir.display()))
+            }
+        }
+    }
+
+    /// Parameters of the run recorded in `dir`
+    pub fn load_parameters(dir: &Path) -> Result<RunParameters> {
+        let path = dir.join("run.json");
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Write the raw and the ANSI-stripped log of a host
+    ///
+    /// `suffix` tells apart several results of the same host, e.g. a
+    /// rollback started from the TUI.
+    pub fn write_host_logs(&self, result: &UpdateResult, suffix: Option<&str>) -> Result<()> {
+        let mut name = result.hostname.replace('/', "_");
+        if let Some(suffix) = suffix {
+            name = format!("{}.{}", name, suffix);
+        }
+        self.write(&format!("{}.raw.log", name), &result.output)?;
+        self.write(&format!("{}.log", name), &readable(&result.output))
+    }
+
+    pub fn write_summary(&self, report: &DeploymentReport) -> Result<()> {
+        report.write(ReportFormat::Json, &self.dir.join("summary.json"))
+    }
+
+    fn write(&self, file: &str, contents: &str) -> Result<()> {
+        let path = self.dir.join(file);
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Output without escape codes, with lines that were overwritten through
+/// carriage returns reduced to their final state
+fn readable(output: &str) -> String {
+    let stripped = strip_ansi_escapes::strip(output.as_bytes());
+    let mut readable = String::new();
+    for line in String::from_utf8_lossy(&stripped).lines() {
+        let line = line.trim_end_matches('\r');
+        readable.push_str(line.rsplit('\r').next().unwrap_or(line));
+        readable.push('\n');
+    }
+    readable
+}
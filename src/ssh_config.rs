@@ -0,0 +1,545 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::discovery::glob_matches;
+use crate::host::Host;
+use crate::paths::home_dir;
+
+/// Nesting limit for `Include`, as in OpenSSH
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// When the options of a block apply
+#[derive(Debug, Clone)]
+pub enum Criteria {
+    /// `Host` patterns, matched against the name the host is known by
+    Host(Vec<String>),
+    /// `Match` criteria with their arguments; only `all`, `host` and
+    /// `originalhost` are evaluated, blocks with any other criterion never apply
+    Match(Vec<(String, String)>),
+}
+
+/// One `Host` or `Match` block of an OpenSSH client config
+#[derive(Debug, Clone)]
+pub struct SshConfigBlock {
+    pub criteria: Criteria,
+    /// Keywords are lowercased, values keep their original case
+    pub options: Vec<(String, String)>,
+}
+
+impl SshConfigBlock {
+    fn new(criteria: Criteria) -> Self {
+        Self {
+            criteria,
+            options: Vec::new(),
+        }
+    }
+
+    /// `alias` is the name the host is known by, `hostname` its `HostName` as
+    /// far as it is resolved when the block is reached
+    fn applies_to(&self, alias: &str, hostname: &str) -> bool {
+        match &self.criteria {
+            Criteria::Host(patterns) => pattern_list_matches(patterns, alias),
+            Criteria::Match(criteria) => criteria.iter().all(|(criterion, argument)| {
+                let patterns: Vec<String> = argument.split(',').map(str::to_string).collect();
+                match criterion.as_str() {
+                    "all" => true,
+                    "host" => pattern_list_matches(&patterns, hostname),
+                    "originalhost" => pattern_list_matches(&patterns, alias),
+                    _ => false,
+                }
+            }),
+        }
+    }
+}
+
+/// A name matches if any pattern matches it and none of the negated (`!`)
+/// patterns do
+fn pattern_list_matches(patterns: &[String], name: &str) -> bool {
+    let name = name.to_lowercase();
+    let mut matched = false;
+    for pattern in patterns {
+        let pattern = pattern.to_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_matches(negated.as_bytes(), name.as_bytes()) => return false,
+            Some(_) => {}
+            None => matched |= glob_matches(pattern.as_bytes(), name.as_bytes()),
+        }
+    }
+    matched
+}
+
+/// Connection settings the config gives for one host
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolvedHost {
+    pub hostname: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Unexpanded, in the order they appear
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+    pub server_alive_interval: Option<u64>,
+}
+
+/// Parsed `~/.ssh/config`
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    pub blocks: Vec<SshConfigBlock>,
+}
+
+impl SshConfig {
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(home_dir()?.join(".ssh/config"))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config = Self::new();
+        config.read_file(path, 0)?;
+        Ok(config)
+    }
+
+    fn new() -> Self {
+        // Options before the first Host line apply to every host
+        Self {
+            blocks: vec![SshConfigBlock::new(Criteria::Host(vec!["*".to_string()]))],
+        }
+    }
+
+    fn read_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read SSH config {}", path.display()))?;
+        self.read_str(&contents, depth);
+        Ok(())
+    }
+
+    fn read_str(&mut self, contents: &str, depth: usize) {
+        for line in contents.lines() {
+            let Some((keyword, value)) = split_line(line) else {
+                continue;
+            };
+
+            match keyword.as_str() {
+                "host" => self.blocks.push(SshConfigBlock::new(Criteria::Host(
+                    value.split_whitespace().map(str::to_string).collect(),
+                ))),
+                "match" => self
+                    .blocks
+                    .push(SshConfigBlock::new(Criteria::Match(parse_match(&value)))),
+                "include" if depth < MAX_INCLUDE_DEPTH => {
+                    let outer = self.blocks.len() - 1;
+                    for included in value.split_whitespace().flat_map(expand_include) {
+                        // Unreadable includes are skipped like missing ones
+                        let _ = self.read_file(&included, depth + 1);
+                    }
+                    // Lines after the Include belong to the block it appeared in
+                    if self.blocks.len() - 1 != outer {
+                        let criteria = self.blocks[outer].criteria.clone();
+                        self.blocks.push(SshConfigBlock::new(criteria));
+                    }
+                }
+                _ => {
+                    if let Some(block) = self.blocks.last_mut() {
+                        block.options.push((keyword, value));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Concrete host aliases, i.e. `Host` patterns without wildcards or negation
+    pub fn host_aliases(&self) -> impl Iterator<Item = &str> {
+        self.blocks
+            .iter()
+            .filter_map(|block| match &block.criteria {
+                Criteria::Host(patterns) => Some(patterns),
+                Criteria::Match(_) => None,
+            })
+            .flatten()
+            .filter(|p| !p.contains(['*', '?', '!']))
+            .map(String::as_str)
+    }
+
+    /// Settings for `alias`; as with OpenSSH, the first value found for an
+    /// option wins, except for `IdentityFile` which accumulates
+    pub fn resolve(&self, alias: &str) -> ResolvedHost {
+        let mut resolved = ResolvedHost::default();
+
+        for block in &self.blocks {
+            let hostname = resolved
+                .hostname
+                .clone()
+                .unwrap_or_else(|| alias.to_string());
+            if !block.applies_to(alias, &hostname) {
+                continue;
+            }
+
+            for (keyword, value) in &block.options {
+                match keyword.as_str() {
+                    "hostname" if resolved.hostname.is_none() => {
+                        resolved.hostname = Some(value.replace("%h", alias));
+                    }
+                    "port" if resolved.port.is_none() => resolved.port = value.parse().ok(),
+                    "user" if resolved.user.is_none() => resolved.user = Some(value.clone()),
+                    "identityfile"
+                        if !value.eq_ignore_ascii_case("none")
+                            && !resolved.identity_files.contains(value) =>
+                    {
+                        resolved.identity_files.push(value.clone());
+                    }
+                    "proxyjump" if resolved.proxy_jump.is_none() => {
+                        resolved.proxy_jump = Some(value.clone());
+                    }
+                    "serveraliveinterval" if resolved.server_alive_interval.is_none() => {
+                        resolved.server_alive_interval = value.parse().ok();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        resolved
+    }
+
+    /// Fill in what the config knows about each host
+    ///
+    /// Values already set on a host (by the inventory or a discovery backend)
+    /// are kept.
+    pub fn apply(&self, hosts: &mut [Host]) {
+        let home = home_dir().ok();
+        for host in hosts {
+            let resolved = self.resolve(&host.name);
+
+            if host.address.is_none() {
+                host.address = resolved.hostname;
+            }
+            if host.port.is_none() {
+                host.port = resolved.port;
+            }
+            if host.user.is_none() {
+                host.user = resolved.user;
+            }
+            if host.proxy_jump.is_none() {
+                // "none" explicitly disables a jump host set by a wider block
+                host.proxy_jump = resolved
+                    .proxy_jump
+                    .filter(|jump| !jump.eq_ignore_ascii_case("none"));
+            }
+            if host.server_alive_interval.is_none() {
+                host.server_alive_interval = resolved.server_alive_interval.filter(|&s| s > 0);
+            }
+            // Without a home directory, files relative to it are left out
+            for identity_file in &resolved.identity_files {
+                let Some(path) = expand_tokens(identity_file, host, home.as_deref()) else {
+                    continue;
+                };
+                if !host.identity_files.contains(&path) {
+                    host.identity_files.push(path);
+                }
+            }
+        }
+    }
+}
+
+/// Split the arguments of a `Match` line into criteria and their arguments
+fn parse_match(value: &str) -> Vec<(String, String)> {
+    let mut words = value.split_whitespace();
+    let mut criteria = Vec::new();
+    while let Some(criterion) = words.next() {
+        let criterion = criterion.to_lowercase();
+        let argument = match criterion.as_str() {
+            "all" | "canonical" | "final" => String::new(),
+            _ => words.next().unwrap_or_default().to_string(),
+        };
+        criteria.push((criterion, argument));
+    }
+    criteria
+}
+
+/// Files an `Include` argument refers to
+///
+/// Relative paths are taken from `~/.ssh`. Wildcards are supported in the
+/// file name.
+fn expand_include(pattern: &str) -> Vec<PathBuf> {
+    let path = if pattern.starts_with('/') {
+        PathBuf::from(pattern)
+    } else {
+        let Ok(home) = home_dir() else {
+            return Vec::new();
+        };
+        match pattern.strip_prefix("~/") {
+            Some(rest) => home.join(rest),
+            None => home.join(".ssh").join(pattern),
+        }
+    };
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !file_name.contains(['*', '?']) {
+        return vec![path];
+    }
+
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut matches: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            glob_matches(
+                file_name.as_bytes(),
+                entry.file_name().to_string_lossy().as_bytes(),
+            )
+        })
+        .map(|entry| entry.path())
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Expand `~` and the `%d`, `%h`, `%r`, `%u` and `%%` tokens of a path option
+///
+/// Returns `None` if the path is relative to the home directory and there
+/// is none.
+fn expand_tokens(value: &str, host: &Host, home: Option<&Path>) -> Option<PathBuf> {
+    let local_user = std::env::var("USER").unwrap_or_default();
+    let home = home.map(Path::to_string_lossy);
+    let value = match value.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home.as_deref()?, rest),
+        None => value.to_string(),
+    };
+
+    let mut expanded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('d') => expanded.push_str(home.as_deref()?),
+            Some('h') => expanded.push_str(host.ssh_address()),
+            Some('r') => expanded.push_str(host.ssh_user()),
+            Some('u') => expanded.push_str(&local_user),
+            Some('%') => expanded.push('%'),
+            Some(other) => {
+                expanded.push('%');
+                expanded.push(other);
+            }
+            None => expanded.push('%'),
+        }
+    }
+
+    Some(PathBuf::from(expanded))
+}
+
+/// Split a config line into its lowercased keyword and value
+///
+/// Accepts both `Keyword value` and `Keyword=value`, drops comments and
+/// surrounding quotes.
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let split_at = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let keyword = line[..split_at].to_lowercase();
+    let value = line[split_at..]
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+        .trim()
+        .trim_matches('"')
+        .to_string();
+
+    Some((keyword, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> SshConfig {
+        let mut config = SshConfig::new();
+        config.read_str(contents, 0);
+        config
+    }
+
+    #[test]
+    fn splits_config_lines() {
+        let cases = [
+            ("HostName example.com", Some(("hostname", "example.com"))),
+            ("  Port=2222", Some(("port", "2222"))),
+            ("User = deploy", Some(("user", "deploy"))),
+            (
+                "IdentityFile \"~/.ssh/my key\"",
+                Some(("identityfile", "~/.ssh/my key")),
+            ),
+            ("# comment", None),
+            ("   ", None),
+            ("Lonely", None),
+        ];
+        for (line, expected) in cases {
+            let split = split_line(line);
+            assert_eq!(
+                split.as_ref().map(|(k, v)| (k.as_str(), v.as_str())),
+                expected,
+                "{line:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn first_value_wins_and_identity_files_accumulate() {
+        let config = parse(
+            "\
+User global
+Host web-01
+    HostName 10.0.0.1
+    IdentityFile ~/.ssh/web
+Host web-*
+    HostName ignored.example.com
+    Port 2222
+    User ignored
+    IdentityFile ~/.ssh/web
+    IdentityFile ~/.ssh/shared
+Host *
+    Port 22
+    IdentityFile none
+    ServerAliveInterval 30
+",
+        );
+
+        assert_eq!(
+            config.resolve("web-01"),
+            ResolvedHost {
+                hostname: Some("10.0.0.1".to_string()),
+                port: Some(2222),
+                user: Some("global".to_string()),
+                identity_files: vec!["~/.ssh/web".to_string(), "~/.ssh/shared".to_string()],
+                proxy_jump: None,
+                server_alive_interval: Some(30),
+            }
+        );
+        assert_eq!(config.resolve("db").port, Some(22));
+        assert_eq!(config.resolve("db").hostname, None);
+    }
+
+    #[test]
+    fn host_patterns_with_negation() {
+        let cases = [
+            ("web-01", Some("web")),
+            ("web-test", None),
+            ("DB-01", Some("db")),
+            ("other", None),
+        ];
+        let config = parse(
+            "\
+Host web-* !web-test
+    User web
+Host db-??
+    User db
+",
+        );
+        for (alias, user) in cases {
+            assert_eq!(config.resolve(alias).user.as_deref(), user, "{alias}");
+        }
+    }
+
+    #[test]
+    fn match_blocks() {
+        let config = parse(
+            "\
+Host short
+    HostName short.internal.example.com
+Match host *.internal.example.com
+    ProxyJump bastion
+Match originalhost direct,short
+    Port 2200
+Match exec \"true\"
+    User never
+Match all
+    User everyone
+",
+        );
+
+        let short = config.resolve("short");
+        assert_eq!(short.proxy_jump.as_deref(), Some("bastion"));
+        assert_eq!(short.port, Some(2200));
+        assert_eq!(short.user.as_deref(), Some("everyone"));
+
+        let direct = config.resolve("direct");
+        assert_eq!(direct.proxy_jump, None);
+        assert_eq!(direct.port, Some(2200));
+    }
+
+    #[test]
+    fn hostname_token_and_aliases() {
+        let config = parse(
+            "\
+Host nix-* *.example.com
+    HostName %h.example.com
+Host alpha beta
+    Port 1
+",
+        );
+        assert_eq!(
+            config.resolve("nix-web").hostname.as_deref(),
+            Some("nix-web.example.com")
+        );
+        assert_eq!(config.host_aliases().collect::<Vec<_>>(), ["alpha", "beta"]);
+    }
+
+    #[test]
+    fn includes_with_wildcards_in_order() {
+        let dir =
+            std::env::temp_dir().join(format!("nix-deploy-ssh-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("10-web.conf"), "Host web\n    Port 2201\n").unwrap();
+        std::fs::write(dir.join("20-db.conf"), "Host db\n    Port 2202\n").unwrap();
+        std::fs::write(dir.join("other.txt"), "Host db\n    Port 9999\n").unwrap();
+
+        let config = parse(&format!(
+            "\
+Host web db
+    Include {}/*.conf
+    User deploy
+",
+            dir.display()
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.resolve("web").port, Some(2201));
+        assert_eq!(config.resolve("db").port, Some(2202));
+        // Lines after the Include still belong to the outer block
+        assert_eq!(config.resolve("db").user.as_deref(), Some("deploy"));
+    }
+
+    #[test]
+    fn expands_path_tokens() {
+        let mut host = Host::new("web").with_address("10.0.0.1");
+        host.user = Some("deploy".to_string());
+        let home = Some(Path::new("/home/alice"));
+
+        let cases = [
+            (
+                "/keys/%r@%h-100%%",
+                home,
+                Some("/keys/deploy@10.0.0.1-100%"),
+            ),
+            ("%d/.ssh/id", home, Some("/home/alice/.ssh/id")),
+            ("~/.ssh/%x", home, Some("/home/alice/.ssh/%x")),
+            ("/keys/%h", None, Some("/keys/10.0.0.1")),
+            ("~/.ssh/id", None, None),
+            ("%d/.ssh/id", None, None),
+        ];
+        for (value, home, expected) in cases {
+            assert_eq!(
+                expand_tokens(value, &host, home),
+                expected.map(PathBuf::from),
+                "{value}"
+            );
+        }
+    }
+}
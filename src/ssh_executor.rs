@@ -1,9 +1,105 @@
 use anyhow::Result;
 use ssh2::Session;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
-use crate::progress::{parse_rebuild_progress, ProgressUpdate, UpdatePhase};
+use crate::host::Host;
+use crate::nix_log::NixLog;
+use crate::progress::{ProgressUpdate, UpdatePhase};
+
+/// Prompt sudo prints on the PTY when it asks for the password, so we know
+/// when to send it
+const SUDO_PROMPT: &str = "[nix-deploy] sudo password: ";
+
+/// Sends the keepalives set up with `Session::set_keepalive`
+///
+/// libssh2 never sends them on its own, only from `keepalive_send`, so loops
+/// that wait on a channel have to call `tick` while it is idle.
+pub struct Keepalive {
+    next: Instant,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Self {
+            next: Instant::now(),
+        }
+    }
+
+    /// Send a keepalive if one is due and return the time until the next one
+    ///
+    /// Without an interval, libssh2 sends nothing; the session is then asked
+    /// again after a second.
+    pub fn tick(&mut self, sess: &Session) -> Duration {
+        let now = Instant::now();
+        if now >= self.next {
+            // A failed send shows up as an error on the next read
+            let seconds = sess.keepalive_send().unwrap_or(0);
+            self.next = now + Duration::from_secs(u64::from(seconds.max(1)));
+        }
+        self.next - now
+    }
+}
+
+/// How commands that need root are run on a host
+#[derive(Debug, Clone, Default)]
+pub enum Sudo {
+    /// Logged in as root, commands run as they are
+    #[default]
+    Off,
+    /// Passwordless sudo; fails instead of prompting
+    NoPassword,
+    /// sudo with the password asked for at startup
+    Password(String),
+}
+
+impl Sudo {
+    /// sudo is used whenever the host isn't logged into as root
+    pub fn for_host(host: &Host, password: Option<&str>) -> Self {
+        match (host.ssh_user(), password) {
+            ("root", _) => Sudo::Off,
+            (_, Some(password)) => Sudo::Password(password.to_string()),
+            (_, None) => Sudo::NoPassword,
+        }
+    }
+
+    /// Wrap `command` so it runs as root
+    ///
+    /// Without a PTY, the password is read from stdin, so it has to be the
+    /// first line written to the channel (see `password_line`). With a PTY,
+    /// sudo prompts on the terminal and the password is sent once the prompt
+    /// shows up.
+    pub fn wrap(&self, command: &str, forward_agent: bool, use_pty: bool) -> String {
+        let options = match self {
+            Sudo::Off => return command.to_string(),
+            Sudo::NoPassword => "-n".to_string(),
+            // -k ignores cached credentials so sudo always reads the password
+            Sudo::Password(_) if use_pty => format!("-k -p {}", shell_quote(SUDO_PROMPT)),
+            Sudo::Password(_) => "-k -S -p ''".to_string(),
+        };
+        // Keep the forwarded agent usable for git over SSH
+        let preserve_env = if forward_agent {
+            " --preserve-env=SSH_AUTH_SOCK"
+        } else {
+            ""
+        };
+        format!(
+            "sudo {}{} sh -c {}",
+            options,
+            preserve_env,
+            shell_quote(command)
+        )
+    }
+
+    /// Line to write to stdin before anything else when running without a PTY
+    pub fn password_line(&self) -> Option<String> {
+        match self {
+            Sudo::Password(password) => Some(format!("{}\n", password)),
+            _ => None,
+        }
+    }
+}
 
 pub fn execute_command_on_channel(
     sess: &Session,
@@ -27,13 +123,83 @@ pub fn execute_command_on_channel(
     Ok((output, exit_status))
 }
 
+/// Run a command as root according to `sudo` and collect its output
+pub fn execute_privileged(
+    sess: &Session,
+    command: &str,
+    forward_agent: bool,
+    sudo: &Sudo,
+) -> Result<(String, i32)> {
+    let mut channel = sess.channel_session()?;
+
+    if forward_agent {
+        channel.request_auth_agent_forwarding()?;
+    }
+
+    channel.exec(&sudo.wrap(command, forward_agent, false))?;
+    if let Some(line) = sudo.password_line() {
+        channel.write_all(line.as_bytes())?;
+    }
+    channel.send_eof()?;
+
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+
+    let exit_status = channel.exit_status()?;
+
+    Ok((output, exit_status))
+}
+
+/// Send one line of command output to the TUI under `phase`
+///
+/// For `Rebuilding` and `Building`, the line is read by `nix_log`, which turns
+/// nix's structured log into readable lines and the progress of the phase.
+pub fn send_output_line(
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    hostname: &str,
+    phase: &UpdatePhase,
+    nix_log: &mut NixLog,
+    line: &str,
+) {
+    let (phase, output_line) = match phase {
+        UpdatePhase::Rebuilding { .. } | UpdatePhase::Building { .. } => {
+            let output_line = nix_log.parse(line);
+            if !nix_log.should_report(output_line.is_some()) {
+                return;
+            }
+            let progress = nix_log.progress();
+            let phase = match phase {
+                UpdatePhase::Rebuilding { .. } => UpdatePhase::Rebuilding { progress },
+                _ => UpdatePhase::Building { progress },
+            };
+            (phase, output_line)
+        }
+        other => (other.clone(), Some(line.to_string())),
+    };
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: hostname.to_string(),
+        phase,
+        output_line,
+    });
+}
+
+/// Run a command and forward its output line by line as `phase` updates
+///
+/// With `use_pty`, a pseudo-terminal is requested so the command doesn't
+/// buffer its output, and ANSI escape codes are stripped from the lines.
+/// The command runs as root according to `sudo`.
+#[allow(clippy::too_many_arguments)]
 pub fn execute_command_streaming(
     sess: &Session,
     command: &str,
     forward_agent: bool,
+    sudo: &Sudo,
     progress_tx: &mpsc::Sender<ProgressUpdate>,
     hostname: &str,
-    is_rebuild: bool,
+    phase: &UpdatePhase,
+    use_pty: bool,
 ) -> Result<(String, i32)> {
     let mut channel = sess.channel_session()?;
 
@@ -43,11 +209,24 @@ pub fn execute_command_streaming(
     }
 
     // Request pseudo-terminal to get unbuffered output
-    if is_rebuild {
+    if use_pty {
         channel.request_pty("xterm", None, None)?;
     }
 
-    channel.exec(command)?;
+    channel.exec(&sudo.wrap(command, forward_agent, use_pty))?;
+
+    // On a PTY the password is sent once sudo prompts for it
+    let pty_password = match sudo {
+        Sudo::Password(password) if use_pty => Some(password.as_str()),
+        _ => None,
+    };
+    if pty_password.is_none()
+        && let Some(line) = sudo.password_line()
+    {
+        channel.write_all(line.as_bytes())?;
+    }
+    let mut password_sent = false;
+    let mut nix_log = NixLog::default();
 
     let mut full_output = String::new();
     let mut buffer = [0u8; 4096];
@@ -56,16 +235,33 @@ pub fn execute_command_streaming(
     let max_consecutive_would_block = 6000; // 6000 * 50ms = 5 minutes
 
     // Read from the channel in chunks
+    let mut keepalive = Keepalive::new();
     loop {
-        match channel.read(&mut buffer) {
+        match read_nonblocking(sess, &mut channel, &mut buffer) {
             Ok(0) => break, // EOF
             Ok(n) => {
                 consecutive_would_block = 0; // Reset counter when data is received
                 let chunk = String::from_utf8_lossy(&buffer[..n]).to_string();
                 full_output.push_str(&chunk);
 
+                if let Some(password) = pty_password {
+                    match full_output.matches(SUDO_PROMPT).count() {
+                        0 => {}
+                        1 if !password_sent => {
+                            channel.write_all(format!("{}\n", password).as_bytes())?;
+                            password_sent = true;
+                        }
+                        1 => {}
+                        // sudo asks again, don't let it wait for input forever
+                        _ => {
+                            let _ = channel.close();
+                            anyhow::bail!("sudo did not accept the password");
+                        }
+                    }
+                }
+
                 // Strip ANSI escape codes if we're using PTY
-                let display_chunk = if is_rebuild {
+                let display_chunk = if use_pty {
                     let stripped = strip_ansi_escapes::strip(chunk.as_bytes());
                     String::from_utf8_lossy(&stripped).to_string()
                 } else {
@@ -73,6 +269,11 @@ pub fn execute_command_streaming(
                 };
 
                 line_buffer.push_str(&display_chunk);
+                // The prompt may arrive split over two reads, so strip it from
+                // the buffered text rather than from each chunk
+                if pty_password.is_some() {
+                    line_buffer = line_buffer.replace(SUDO_PROMPT, "");
+                }
 
                 // Process complete lines
                 // Handle both \n and \r\n as line terminators
@@ -91,31 +292,7 @@ pub fn execute_command_streaming(
 
                         let trimmed = line.trim();
                         if !trimmed.is_empty() {
-                            // Parse rebuild progress if this is a rebuild command
-                            if is_rebuild {
-                                if let Some(progress) = parse_rebuild_progress(trimmed) {
-                                    let _ = progress_tx.try_send(ProgressUpdate {
-                                        hostname: hostname.to_string(),
-                                        phase: UpdatePhase::Rebuilding { progress },
-                                        output_line: Some(trimmed.to_string()),
-                                    });
-                                } else {
-                                    let _ = progress_tx.try_send(ProgressUpdate {
-                                        hostname: hostname.to_string(),
-                                        phase: UpdatePhase::Rebuilding {
-                                            progress: String::new(),
-                                        },
-                                        output_line: Some(trimmed.to_string()),
-                                    });
-                                }
-                            } else {
-                                // For non-rebuild commands, just send the output line
-                                let _ = progress_tx.try_send(ProgressUpdate {
-                                    hostname: hostname.to_string(),
-                                    phase: UpdatePhase::PullingGit,
-                                    output_line: Some(trimmed.to_string()),
-                                });
-                            }
+                            send_output_line(progress_tx, hostname, phase, &mut nix_log, trimmed);
                         }
                     } else if let Some(cr_pos) = line_buffer.find('\r') {
                         // Found a \r without \n - this is a progress update that overwrites the line
@@ -136,7 +313,8 @@ pub fn execute_command_streaming(
                 }
             }
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                // No data available, sleep briefly
+                // No data available, keep the connection alive and sleep briefly
+                keepalive.tick(sess);
                 consecutive_would_block += 1;
                 if consecutive_would_block > max_consecutive_would_block {
                     let error_msg = format!(
@@ -177,34 +355,75 @@ pub fn execute_command_streaming(
 
     // Process any remaining content in line buffer
     if !line_buffer.trim().is_empty() {
-        let trimmed = line_buffer.trim();
-        if is_rebuild {
-            if let Some(progress) = parse_rebuild_progress(trimmed) {
-                let _ = progress_tx.try_send(ProgressUpdate {
-                    hostname: hostname.to_string(),
-                    phase: UpdatePhase::Rebuilding { progress },
-                    output_line: Some(trimmed.to_string()),
-                });
-            } else {
-                let _ = progress_tx.try_send(ProgressUpdate {
-                    hostname: hostname.to_string(),
-                    phase: UpdatePhase::Rebuilding {
-                        progress: String::new(),
-                    },
-                    output_line: Some(trimmed.to_string()),
-                });
-            }
-        } else {
-            let _ = progress_tx.try_send(ProgressUpdate {
-                hostname: hostname.to_string(),
-                phase: UpdatePhase::PullingGit,
-                output_line: Some(trimmed.to_string()),
-            });
-        }
+        send_output_line(
+            progress_tx,
+            hostname,
+            phase,
+            &mut nix_log,
+            line_buffer.trim(),
+        );
     }
 
     // Wait for channel to close and get exit status
     channel.wait_close()?;
     let exit_status = channel.exit_status()?;
+    if pty_password.is_some() {
+        full_output = strip_sudo_prompt(&full_output);
+    }
     Ok((full_output, exit_status))
 }
+
+/// Remove sudo's password prompt and the newline it prints once the
+/// password is entered, which aren't output of the command
+fn strip_sudo_prompt(output: &str) -> String {
+    output
+        .replace(&format!("{}\r\n", SUDO_PROMPT), "")
+        .replace(SUDO_PROMPT, "")
+}
+
+/// Read from a stream of a channel of `sess` without waiting for data
+///
+/// The session stays blocking for everything else, like writing the sudo
+/// password.
+pub fn read_nonblocking(
+    sess: &Session,
+    stream: &mut impl Read,
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    sess.set_blocking(false);
+    let result = stream.read(buffer);
+    sess.set_blocking(true);
+    result
+}
+
+/// Quote a value for use as a single word in a POSIX shell command
+pub fn shell_quote(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:@%+=,".contains(c))
+    {
+        return value.to_string();
+    }
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_sudo_prompt_from_output() {
+        let cases = [
+            (
+                "[nix-deploy] sudo password: \r\nbuilding...\r\n",
+                "building...\r\n",
+            ),
+            ("[nix-deploy] sudo password: done", "done"),
+            ("no prompt\n", "no prompt\n"),
+        ];
+        for (output, expected) in cases {
+            assert_eq!(strip_sudo_prompt(output), expected);
+        }
+    }
+}
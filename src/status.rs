@@ -0,0 +1,293 @@
+use anyhow::Result;
+use futures::future::join_all;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::host::Host;
+use crate::ssh_executor::{Sudo, execute_privileged};
+use crate::updater::{ConnectOptions, connect_session};
+
+/// Read-only snapshot of how far a host has drifted from its configuration
+#[derive(Debug, Clone, Default)]
+pub struct HostStatus {
+    /// HEAD of /etc/nixos
+    pub git_rev: Option<String>,
+    /// Tip of the branch the checkout tracks, according to `git ls-remote`
+    pub upstream_rev: Option<String>,
+    /// Commits between HEAD and the upstream revision, if that revision is
+    /// known locally
+    pub commits_behind: Option<u32>,
+    /// Local commits that aren't upstream
+    pub commits_ahead: Option<u32>,
+    pub dirty: bool,
+    pub generation: Option<u64>,
+    /// Seconds since the current generation was created
+    pub generation_age: Option<i64>,
+    pub nixos_version: Option<String>,
+    /// `/run/booted-system` differs from `/run/current-system`
+    pub reboot_pending: bool,
+}
+
+// Everything is printed as key=value lines so a single command covers all fields
+const STATUS_SCRIPT: &str = r#"
+if cd /etc/nixos 2>/dev/null && git rev-parse HEAD >/dev/null 2>&1; then
+    echo "rev=$(git rev-parse HEAD)"
+    branch=$(git symbolic-ref --short -q HEAD)
+    remote=$(git config "branch.$branch.remote")
+    merge=$(git config "branch.$branch.merge")
+    if [ -n "$remote" ] && [ -n "$merge" ]; then
+        upstream=$(timeout 20 git ls-remote "$remote" "$merge" 2>/dev/null | cut -f1)
+        echo "upstream=$upstream"
+        [ -n "$upstream" ] && echo "ahead_behind=$(git rev-list --left-right --count HEAD...$upstream 2>/dev/null)"
+    fi
+    echo "dirty=$(git status --porcelain 2>/dev/null | wc -l)"
+fi
+link=$(readlink /nix/var/nix/profiles/system)
+echo "profile=$link"
+echo "created=$(stat -c %Y /nix/var/nix/profiles/$link)"
+echo "now=$(date +%s)"
+echo "version=$(nixos-version 2>/dev/null)"
+echo "booted=$(readlink -f /run/booted-system)"
+echo "current=$(readlink -f /run/current-system)"
+"#;
+
+impl HostStatus {
+    fn parse(output: &str) -> Self {
+        let mut status = Self::default();
+        let mut created: Option<i64> = None;
+        let mut now: Option<i64> = None;
+        let mut booted = None;
+        let mut current = None;
+
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+
+            match key {
+                "rev" => status.git_rev = Some(value.to_string()),
+                "upstream" => status.upstream_rev = Some(value.to_string()),
+                "ahead_behind" => {
+                    let mut counts = value.split_whitespace().map(|c| c.parse().ok());
+                    status.commits_ahead = counts.next().flatten();
+                    status.commits_behind = counts.next().flatten();
+                }
+                "dirty" => status.dirty = value != "0",
+                "profile" => {
+                    status.generation = value
+                        .strip_prefix("system-")
+                        .and_then(|v| v.strip_suffix("-link"))
+                        .and_then(|v| v.parse().ok())
+                }
+                "created" => created = value.parse().ok(),
+                "now" => now = value.parse().ok(),
+                "version" => status.nixos_version = Some(value.to_string()),
+                "booted" => booted = Some(value.to_string()),
+                "current" => current = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        status.generation_age = created.zip(now).map(|(created, now)| now - created);
+        status.reboot_pending = booted.is_some() && current.is_some() && booted != current;
+        status
+    }
+
+    /// The checkout is missing commits of the branch it tracks
+    pub fn is_behind(&self) -> bool {
+        match (&self.git_rev, &self.upstream_rev) {
+            (Some(rev), Some(upstream)) => rev != upstream && self.commits_behind != Some(0),
+            _ => false,
+        }
+    }
+
+    pub fn has_drift(&self) -> bool {
+        self.is_behind() || self.dirty || self.reboot_pending
+    }
+
+    /// Short git state, e.g. `1a2b3c4 (3 behind, dirty)`
+    pub fn git_summary(&self) -> String {
+        let Some(rev) = &self.git_rev else {
+            return "no git repo".to_string();
+        };
+
+        let mut notes = Vec::new();
+        if let Some(count) = self.commits_ahead.filter(|&count| count > 0) {
+            notes.push(format!("{} ahead", count));
+        }
+        if self.is_behind() {
+            notes.push(match self.commits_behind {
+                Some(count) => format!("{} behind", count),
+                None => "behind".to_string(),
+            });
+        } else if self.upstream_rev.is_none() {
+            notes.push("upstream unknown".to_string());
+        }
+        if self.dirty {
+            notes.push("dirty".to_string());
+        }
+
+        let short = &rev[..rev.len().min(7)];
+        if notes.is_empty() {
+            short.to_string()
+        } else {
+            format!("{} ({})", short, notes.join(", "))
+        }
+    }
+
+    pub fn generation_summary(&self) -> String {
+        match (self.generation, self.generation_age) {
+            (Some(generation), Some(age)) => format!("gen {} ({})", generation, format_age(age)),
+            (Some(generation), None) => format!("gen {}", generation),
+            _ => "gen ?".to_string(),
+        }
+    }
+
+    /// Everything on one line for the selector
+    pub fn summary(&self) -> String {
+        let mut parts = vec![
+            self.git_summary(),
+            self.generation_summary(),
+            self.nixos_version
+                .clone()
+                .unwrap_or_else(|| "?".to_string()),
+        ];
+        if self.reboot_pending {
+            parts.push("reboot pending".to_string());
+        }
+        parts.join(" · ")
+    }
+}
+
+/// `3d 4h`, `5h 12m` or `12m`
+pub fn format_age(seconds: i64) -> String {
+    let minutes = seconds.max(0) / 60;
+    let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+pub fn query_status_blocking(
+    host: &Host,
+    connect: &ConnectOptions,
+    forward_agent: bool,
+    sudo_password: Option<&str>,
+) -> Result<HostStatus> {
+    // Nobody watches the progress of status queries
+    let (quiet_tx, _) = mpsc::channel(1);
+
+    let sess = match connect_session(host, connect, Duration::from_secs(10), &quiet_tx)? {
+        Ok(sess) => sess,
+        Err(error) => anyhow::bail!("{}", error),
+    };
+
+    // git refuses to read /etc/nixos as anyone but its owner
+    let sudo = Sudo::for_host(host, sudo_password);
+    let (output, _) = execute_privileged(&sess, STATUS_SCRIPT, forward_agent, &sudo)?;
+    Ok(HostStatus::parse(&output))
+}
+
+/// Query all hosts at once; errors are kept per host
+pub async fn query_statuses(
+    hosts: &[Host],
+    connect: &ConnectOptions,
+    forward_agent: bool,
+    sudo_password: Option<&str>,
+) -> Vec<std::result::Result<HostStatus, String>> {
+    let handles = hosts.iter().cloned().map(|host| {
+        let connect = connect.clone();
+        let sudo_password = sudo_password.map(str::to_string);
+        tokio::task::spawn_blocking(move || {
+            query_status_blocking(&host, &connect, forward_agent, sudo_password.as_deref())
+        })
+    });
+
+    join_all(handles)
+        .await
+        .into_iter()
+        .map(|joined| match joined {
+            Ok(Ok(status)) => Ok(status),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(format!("Task error: {}", e)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "\
+rev=1a2b3c4d5e6f7a8b9c0d
+upstream=9f8e7d6c5b4a
+ahead_behind=1\t3
+dirty=2
+profile=system-42-link
+created=1000
+now=94600
+version=24.05.1234 (Uakari)
+booted=/nix/store/aaa-nixos-system
+current=/nix/store/bbb-nixos-system
+";
+
+    #[test]
+    fn parses_all_fields() {
+        let status = HostStatus::parse(OUTPUT);
+
+        assert_eq!(status.git_rev.as_deref(), Some("1a2b3c4d5e6f7a8b9c0d"));
+        assert_eq!(status.upstream_rev.as_deref(), Some("9f8e7d6c5b4a"));
+        assert_eq!(status.commits_ahead, Some(1));
+        assert_eq!(status.commits_behind, Some(3));
+        assert!(status.dirty);
+        assert_eq!(status.generation, Some(42));
+        assert_eq!(status.generation_age, Some(93600));
+        assert_eq!(status.nixos_version.as_deref(), Some("24.05.1234 (Uakari)"));
+        assert!(status.reboot_pending);
+        assert!(status.has_drift());
+        assert_eq!(status.git_summary(), "1a2b3c4 (1 ahead, 3 behind, dirty)");
+        assert_eq!(status.generation_summary(), "gen 42 (1d 2h)");
+    }
+
+    #[test]
+    fn ahead_only_is_not_behind() {
+        let status = HostStatus::parse("rev=abc\nupstream=def\nahead_behind=2\t0\ndirty=0\n");
+        assert!(!status.is_behind());
+        assert!(!status.has_drift());
+        assert_eq!(status.git_summary(), "abc (2 ahead)");
+    }
+
+    #[test]
+    fn missing_counts_or_upstream() {
+        let status = HostStatus::parse("rev=abc\nupstream=def\ndirty=0\n");
+        assert!(status.is_behind());
+
+        let status = HostStatus::parse("rev=abc\nupstream=\ndirty=0\n");
+        assert!(!status.is_behind());
+        assert_eq!(status.git_summary(), "abc (upstream unknown)");
+    }
+
+    #[test]
+    fn missing_repo_and_profile() {
+        let status = HostStatus::parse("profile=\nversion=\n");
+        assert_eq!(status.git_summary(), "no git repo");
+        assert_eq!(status.generation_summary(), "gen ?");
+        assert!(!status.reboot_pending);
+    }
+
+    #[test]
+    fn formats_ages() {
+        assert_eq!(format_age(-5), "0m");
+        assert_eq!(format_age(59 * 60), "59m");
+        assert_eq!(format_age(5 * 3600 + 12 * 60), "5h 12m");
+        assert_eq!(format_age(3 * 86400 + 4 * 3600), "3d 4h");
+    }
+}
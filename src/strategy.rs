@@ -0,0 +1,197 @@
+use futures::future::join_all;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::host::Host;
+use crate::progress::{ProgressUpdate, UpdatePhase};
+use crate::updater::{UpdateOptions, UpdateResult, update_server_with_progress};
+
+/// Order and concurrency in which the selected hosts are updated
+#[derive(Debug, Clone, Default)]
+pub struct Strategy {
+    /// Maximum number of hosts updated at the same time
+    pub parallel: Option<usize>,
+    /// Tags whose hosts are deployed as separate waves, in this order; hosts
+    /// without any of them form the last wave
+    pub waves: Vec<String>,
+    /// Update the first host alone and only continue if it succeeds
+    pub canary: bool,
+    /// Skip all hosts that haven't started yet once one fails
+    pub stop_on_failure: bool,
+}
+
+impl Strategy {
+    /// Indices of `hosts` grouped into waves that run one after another
+    pub fn plan(&self, hosts: &[Host]) -> Vec<Vec<usize>> {
+        let mut remaining: Vec<usize> = (0..hosts.len()).collect();
+        let mut waves = Vec::new();
+
+        for tag in &self.waves {
+            let (wave, rest): (Vec<usize>, Vec<usize>) = remaining
+                .into_iter()
+                .partition(|&i| hosts[i].tags.contains(tag));
+            remaining = rest;
+            if !wave.is_empty() {
+                waves.push(wave);
+            }
+        }
+        if !remaining.is_empty() {
+            waves.push(remaining);
+        }
+
+        if self.canary
+            && let Some(first) = waves.first_mut()
+            && first.len() > 1
+        {
+            let canary = first.remove(0);
+            waves.insert(0, vec![canary]);
+        }
+
+        waves
+    }
+}
+
+/// Update `hosts` according to `strategy`
+///
+/// Results are returned in the order of `hosts`. A failing canary, or any
+/// failure with `stop_on_failure`, leaves the hosts that haven't started yet
+/// in `Pending` and marks them as skipped.
+pub async fn deploy(
+    hosts: Vec<Host>,
+    options: UpdateOptions,
+    strategy: Strategy,
+    progress_tx: mpsc::Sender<ProgressUpdate>,
+) -> Vec<UpdateResult> {
+    let permits = strategy
+        .parallel
+        .unwrap_or(Semaphore::MAX_PERMITS)
+        .clamp(1, Semaphore::MAX_PERMITS);
+    let semaphore = Arc::new(Semaphore::new(permits));
+    let halted = Arc::new(AtomicBool::new(false));
+    let mut results: Vec<Option<UpdateResult>> = vec![None; hosts.len()];
+
+    for (wave_index, wave) in strategy.plan(&hosts).into_iter().enumerate() {
+        let is_canary = strategy.canary && wave_index == 0;
+        let stop_on_failure = strategy.stop_on_failure || is_canary;
+
+        let handles: Vec<_> = wave
+            .iter()
+            .map(|&i| {
+                let host = hosts[i].clone();
+                let options = options.clone();
+                let tx = progress_tx.clone();
+                let semaphore = semaphore.clone();
+                let halted = halted.clone();
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire_owned().await;
+                    if halted.load(Ordering::SeqCst) {
+                        return UpdateResult::skipped(
+                            &host.name,
+                            "deployment stopped after a failure",
+                            &tx,
+                        );
+                    }
+
+                    let result = update_host(host, options, tx).await;
+                    if !result.success && stop_on_failure {
+                        halted.store(true, Ordering::SeqCst);
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        for (&i, task_result) in wave.iter().zip(join_all(handles).await) {
+            results[i] = Some(task_result.unwrap_or_else(|e| {
+                UpdateResult::error(&hosts[i].name, format!("Task error: {}", e))
+            }));
+        }
+    }
+
+    results.into_iter().flatten().collect()
+}
+
+/// Update a single host, turning errors into a failed result
+pub async fn update_host(
+    host: Host,
+    options: UpdateOptions,
+    tx: mpsc::Sender<ProgressUpdate>,
+) -> UpdateResult {
+    match update_server_with_progress(&host, options, tx.clone()).await {
+        Ok(result) => result,
+        Err(e) => {
+            let error_msg = format!("Error: {}", e);
+            // Send error to TUI immediately
+            let _ = tx.try_send(ProgressUpdate {
+                hostname: host.name.clone(),
+                phase: UpdatePhase::Failed {
+                    reason: error_msg.clone(),
+                },
+                output_line: Some(error_msg.clone()),
+            });
+            UpdateResult::error(&host.name, error_msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(name: &str, tags: &[&str]) -> Host {
+        let mut host = Host::new(name);
+        host.tags = tags.iter().map(|t| t.to_string()).collect();
+        host
+    }
+
+    fn hosts() -> Vec<Host> {
+        vec![
+            tagged("web-01", &["web"]),
+            tagged("db-01", &["db"]),
+            tagged("web-02", &["web", "db"]),
+            tagged("misc", &[]),
+        ]
+    }
+
+    #[test]
+    fn without_waves_all_hosts_run_together() {
+        assert_eq!(Strategy::default().plan(&hosts()), [vec![0, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn waves_follow_tag_order_and_untagged_hosts_come_last() {
+        let strategy = Strategy {
+            waves: vec!["db".to_string(), "web".to_string(), "none".to_string()],
+            ..Strategy::default()
+        };
+        // web-02 has both tags and goes with the first wave listing one of them
+        assert_eq!(strategy.plan(&hosts()), [vec![1, 2], vec![0], vec![3]]);
+    }
+
+    #[test]
+    fn canary_is_split_off_the_first_wave() {
+        let strategy = Strategy {
+            waves: vec!["web".to_string()],
+            canary: true,
+            ..Strategy::default()
+        };
+        assert_eq!(strategy.plan(&hosts()), [vec![0], vec![2], vec![1, 3]]);
+    }
+
+    #[test]
+    fn canary_of_a_single_host_wave_stays_in_place() {
+        let strategy = Strategy {
+            waves: vec!["db".to_string()],
+            canary: true,
+            ..Strategy::default()
+        };
+        let hosts = [tagged("db-01", &["db"]), tagged("web-01", &[])];
+        assert_eq!(strategy.plan(&hosts), [vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn no_hosts_no_waves() {
+        assert!(Strategy::default().plan(&[]).is_empty());
+    }
+}
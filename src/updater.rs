@@ -1,42 +1,228 @@
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use ssh2::Session;
+use std::collections::HashMap;
 use std::net::{TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
-use crate::progress::{ProgressUpdate, UpdatePhase};
-use crate::ssh_executor::{execute_command_on_channel, execute_command_streaming};
+use crate::action::{DryActivation, RebuildAction};
+use crate::closure::{LocalBuild, copy_closure};
+use crate::health::HealthSettings;
+use crate::host::Host;
+use crate::identity::{certificate_for, is_encrypted, key_candidates};
+use crate::jump::JumpPool;
+use crate::known_hosts::{HostKeyChecking, prefer_known_key_types, verify_host_key};
+use crate::magic_rollback::Watchdog;
+use crate::nix_log::LOG_FORMAT_ARGS;
+use crate::plan::{ClosureDiff, plan_server_blocking};
+use crate::progress::{NixProgress, ProgressUpdate, UpdatePhase};
+use crate::reboot::reboot_and_wait;
+use crate::rebuild_error::{ExtractedError, extract_error};
+use crate::rollback::rollback_server_blocking;
+use crate::ssh_config::SshConfig;
+use crate::ssh_executor::{
+    Sudo, execute_command_on_channel, execute_command_streaming, execute_privileged,
+};
+
+/// What to do on each host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Deploy the latest configuration
+    Deploy,
+    /// Switch to an earlier generation, by default the one before the current
+    Rollback { generation: Option<u64> },
+    /// Build the latest configuration and diff it against the running system
+    Plan,
+}
+
+/// How SSH connections to the hosts are established
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    pub host_key_checking: HostKeyChecking,
+    /// Used to look up the settings of jump hosts
+    pub ssh_config: SshConfig,
+    /// Jump host sessions, shared by all hosts of the run
+    pub jumps: Arc<JumpPool>,
+    /// Passphrases of encrypted keys, asked for once the hosts are selected
+    pub passphrases: HashMap<PathBuf, String>,
+}
+
+/// Settings shared by all hosts of a run
+#[derive(Debug, Clone)]
+pub struct UpdateOptions {
+    pub operation: Operation,
+    pub action: RebuildAction,
+    pub forward_agent: bool,
+    pub connect: ConnectOptions,
+    pub command: Option<String>,
+    pub run_after: bool,
+    /// Build locally and push closures instead of git pull + nixos-rebuild
+    pub local_build: Option<LocalBuild>,
+    /// Roll back automatically unless a fresh connection succeeds within this
+    /// time after activation
+    pub magic_rollback: Option<Duration>,
+    /// Checks run after activation before a host counts as updated
+    pub health: HealthSettings,
+    /// Reboot after a boot deployment, waiting this long for the host to return
+    pub reboot: Option<Duration>,
+    /// Password for sudo on hosts that aren't logged into as root
+    pub sudo_password: Option<String>,
+}
+
+/// Outcome of updating a single host
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateResult {
+    pub hostname: String,
+    pub success: bool,
+    /// Not attempted because the deployment stopped early
+    pub skipped: bool,
+    pub output: String,
+    pub commands: Vec<CommandRecord>,
+    pub git_rev_before: Option<String>,
+    pub git_rev_after: Option<String>,
+    /// System profile generation before the rebuild
+    pub previous_generation: Option<u64>,
+    /// System profile generation after the rebuild
+    pub generation: Option<u64>,
+    /// Unit changes found by a dry activation
+    pub dry_activation: Option<DryActivation>,
+    /// Package changes found while planning
+    pub plan: Option<ClosureDiff>,
+    /// Components of the running system that differ from the booted one
+    pub reboot_required: Vec<String>,
+    /// Error found in the output of the last command, if it failed
+    pub error: Option<ExtractedError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRecord {
+    pub command: String,
+    pub exit_code: i32,
+    pub duration_secs: f64,
+}
+
+impl UpdateResult {
+    pub fn new(hostname: &str) -> Self {
+        Self {
+            hostname: hostname.to_string(),
+            success: false,
+            skipped: false,
+            output: String::new(),
+            commands: Vec::new(),
+            git_rev_before: None,
+            git_rev_after: None,
+            previous_generation: None,
+            generation: None,
+            dry_activation: None,
+            plan: None,
+            reboot_required: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Result for an update that errored before producing any output
+    pub fn error(hostname: &str, error_msg: String) -> Self {
+        let mut result = Self::new(hostname);
+        result.output.push_str(&error_msg);
+        result.output.push('\n');
+        result
+    }
+
+    /// Result for a host that was left out after an earlier failure
+    pub fn skipped(
+        hostname: &str,
+        reason: &str,
+        progress_tx: &mpsc::Sender<ProgressUpdate>,
+    ) -> Self {
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: hostname.to_string(),
+            phase: UpdatePhase::Pending { skipped: true },
+            output_line: Some(format!("Skipped: {}", reason)),
+        });
+
+        let mut result = Self::error(hostname, format!("Skipped: {}", reason));
+        result.skipped = true;
+        result
+    }
+
+    /// Keep a command's output and exit code for the report
+    pub fn record_command(
+        &mut self,
+        command: &str,
+        output: &str,
+        exit_code: i32,
+        started: Instant,
+    ) {
+        self.output
+            .push_str(&format!("$ {}\n{}\n", command, output));
+        self.error = if exit_code != 0 {
+            extract_error(output)
+        } else {
+            None
+        };
+        self.commands.push(CommandRecord {
+            command: command.to_string(),
+            exit_code,
+            duration_secs: started.elapsed().as_secs_f64(),
+        });
+    }
+
+    /// Mark the update as failed and report the reason to the TUI
+    ///
+    /// If an error could be extracted from the failed command's output, it
+    /// replaces `error_msg` as the reason shown in the server list.
+    pub fn fail(mut self, error_msg: String, progress_tx: &mpsc::Sender<ProgressUpdate>) -> Self {
+        self.success = false;
+        self.output.push_str(&error_msg);
+        self.output.push('\n');
+
+        let (reason, output_line) = match &self.error {
+            Some(error) => (
+                error.summary.clone(),
+                Some(format!("{}\n{}", error_msg, error.details.join("\n"))),
+            ),
+            None => (error_msg, None),
+        };
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: self.hostname.clone(),
+            phase: UpdatePhase::Failed { reason },
+            output_line,
+        });
+
+        self
+    }
+}
 
 pub async fn update_server_with_progress(
-    server_info: &str,
-    use_boot: bool,
-    forward_agent: bool,
-    command: Option<String>,
-    run_after: bool,
+    host: &Host,
+    options: UpdateOptions,
     progress_tx: mpsc::Sender<ProgressUpdate>,
-) -> Result<(String, bool, String)> {
-    let server_info = server_info.to_string();
+) -> Result<UpdateResult> {
+    let host = host.clone();
 
     // Wrap all blocking SSH operations in spawn_blocking
-    tokio::task::spawn_blocking(move || {
-        update_server_blocking(
-            &server_info,
-            use_boot,
-            forward_agent,
-            command,
-            run_after,
-            progress_tx,
-        )
+    tokio::task::spawn_blocking(move || match options.operation {
+        Operation::Deploy => update_server_blocking(&host, &options, progress_tx),
+        Operation::Rollback { generation } => {
+            rollback_server_blocking(&host, generation, &options, progress_tx)
+        }
+        Operation::Plan => plan_server_blocking(&host, &options, progress_tx),
     })
     .await?
 }
 
 fn authenticate_ssh_session(
     sess: &Session,
-    username: &str,
-    hostname: &str,
+    host: &Host,
+    passphrases: &HashMap<PathBuf, String>,
     progress_tx: &mpsc::Sender<ProgressUpdate>,
 ) -> Result<bool> {
+    let username = host.ssh_user();
+    let hostname = host.name.as_str();
     let mut authenticated = false;
     let mut auth_errors = Vec::new();
 
@@ -48,32 +234,39 @@ fn authenticate_ssh_session(
         output_line: Some("Trying file-based SSH keys...".to_string()),
     });
 
-    let home = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
-    let key_paths = vec![
-        format!("{}/.ssh/id_ed25519", home),
-        format!("{}/.ssh/id_rsa", home),
-        format!("{}/.ssh/id_ecdsa", home),
-        format!("{}/.ssh/id_dsa", home),
-    ];
-
-    for key_path in &key_paths {
-        if std::path::Path::new(&key_path).exists() {
-            match sess.userauth_pubkey_file(username, None, std::path::Path::new(&key_path), None) {
-                Ok(()) => {
-                    if sess.authenticated() {
-                        authenticated = true;
-                        let _ = progress_tx.try_send(ProgressUpdate {
-                            hostname: hostname.to_string(),
-                            phase: UpdatePhase::Connecting,
-                            output_line: Some(format!("✓ Authenticated with key: {}", key_path)),
-                        });
-                        return Ok(authenticated);
-                    }
-                }
-                Err(e) => {
-                    auth_errors.push(format!("Key {}: {}", key_path, e));
+    for key_path in key_candidates(host) {
+        let passphrase = passphrases.get(&key_path).map(String::as_str);
+        if passphrase.is_none() && is_encrypted(&key_path) {
+            // Left to the agent, if it holds the key
+            auth_errors.push(format!("Key {}: no passphrase", key_path.display()));
+            continue;
+        }
+
+        let certificate = certificate_for(&key_path);
+        match sess.userauth_pubkey_file(username, certificate.as_deref(), &key_path, passphrase) {
+            Ok(()) => {
+                if sess.authenticated() {
+                    authenticated = true;
+                    let with_certificate = if certificate.is_some() {
+                        " (with certificate)"
+                    } else {
+                        ""
+                    };
+                    let _ = progress_tx.try_send(ProgressUpdate {
+                        hostname: hostname.to_string(),
+                        phase: UpdatePhase::Connecting,
+                        output_line: Some(format!(
+                            "✓ Authenticated with key: {}{}",
+                            key_path.display(),
+                            with_certificate
+                        )),
+                    });
+                    return Ok(authenticated);
                 }
             }
+            Err(e) => {
+                auth_errors.push(format!("Key {}: {}", key_path.display(), e));
+            }
         }
     }
 
@@ -109,52 +302,52 @@ fn authenticate_ssh_session(
         output_line: Some("Trying manual agent key iteration...".to_string()),
     });
 
-    if let Ok(mut agent) = sess.agent() {
-        if let Ok(()) = agent.connect() {
-            if let Ok(()) = agent.list_identities() {
-                if let Ok(identities) = agent.identities() {
-                    let _ = progress_tx.try_send(ProgressUpdate {
-                        hostname: hostname.to_string(),
-                        phase: UpdatePhase::Connecting,
-                        output_line: Some(format!("Found {} key(s) in agent", identities.len())),
-                    });
+    if let Ok(mut agent) = sess.agent()
+        && let Ok(()) = agent.connect()
+    {
+        if let Ok(()) = agent.list_identities()
+            && let Ok(identities) = agent.identities()
+        {
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::Connecting,
+                output_line: Some(format!("Found {} key(s) in agent", identities.len())),
+            });
+
+            for (idx, identity) in identities.iter().enumerate() {
+                if sess.authenticated() {
+                    break;
+                }
+
+                let comment = identity.comment();
+                let _ = progress_tx.try_send(ProgressUpdate {
+                    hostname: hostname.to_string(),
+                    phase: UpdatePhase::Connecting,
+                    output_line: Some(format!("  Trying key #{}: {}", idx + 1, comment)),
+                });
 
-                    for (idx, identity) in identities.iter().enumerate() {
+                match agent.userauth(username, identity) {
+                    Ok(()) => {
                         if sess.authenticated() {
+                            authenticated = true;
+                            let _ = progress_tx.try_send(ProgressUpdate {
+                                hostname: hostname.to_string(),
+                                phase: UpdatePhase::Connecting,
+                                output_line: Some(format!(
+                                    "✓ Authenticated with agent key: {}",
+                                    comment
+                                )),
+                            });
                             break;
                         }
-
-                        let comment = identity.comment();
-                        let _ = progress_tx.try_send(ProgressUpdate {
-                            hostname: hostname.to_string(),
-                            phase: UpdatePhase::Connecting,
-                            output_line: Some(format!("  Trying key #{}: {}", idx + 1, comment)),
-                        });
-
-                        match agent.userauth(username, identity) {
-                            Ok(()) => {
-                                if sess.authenticated() {
-                                    authenticated = true;
-                                    let _ = progress_tx.try_send(ProgressUpdate {
-                                        hostname: hostname.to_string(),
-                                        phase: UpdatePhase::Connecting,
-                                        output_line: Some(format!(
-                                            "✓ Authenticated with agent key: {}",
-                                            comment
-                                        )),
-                                    });
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                auth_errors.push(format!("Agent key '{}': {}", comment, e));
-                            }
-                        }
+                    }
+                    Err(e) => {
+                        auth_errors.push(format!("Agent key '{}': {}", comment, e));
                     }
                 }
             }
-            let _ = agent.disconnect();
         }
+        let _ = agent.disconnect();
     }
 
     if !authenticated {
@@ -175,110 +368,110 @@ fn authenticate_ssh_session(
     Ok(authenticated)
 }
 
-fn update_server_blocking(
-    server_info: &str,
-    use_boot: bool,
-    forward_agent: bool,
-    command: Option<String>,
-    run_after: bool,
-    progress_tx: mpsc::Sender<ProgressUpdate>,
-) -> Result<(String, bool, String)> {
-    let parts: Vec<&str> = server_info.split(':').collect();
-    if parts.len() < 2 {
-        return Ok((
-            server_info.to_string(),
-            false,
-            "Invalid server info format".to_string(),
-        ));
-    }
-
-    let hostname = parts[0];
-    let ip = parts[1];
+/// How the output of a recorded command reaches the TUI
+enum CommandOutput {
+    /// Collected and only kept in the result
+    Buffered,
+    /// Forwarded line by line under the given phase
+    Streamed { phase: UpdatePhase, use_pty: bool },
+}
 
-    let flake_hostname = if hostname.starts_with("nix") {
-        &hostname[3..]
-    } else {
-        hostname
+/// Run a command whose exit code and duration end up in the report
+fn run_recorded(
+    sess: &Session,
+    command: &str,
+    forward_agent: bool,
+    sudo: &Sudo,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+    output: CommandOutput,
+) -> Result<i32> {
+    let started = Instant::now();
+    let (buf, exit_status) = match output {
+        CommandOutput::Streamed { phase, use_pty } => execute_command_streaming(
+            sess,
+            command,
+            forward_agent,
+            sudo,
+            progress_tx,
+            &result.hostname,
+            &phase,
+            use_pty,
+        )?,
+        CommandOutput::Buffered => execute_privileged(sess, command, forward_agent, sudo)?,
     };
 
-    // Send connecting phase
-    let _ = progress_tx.try_send(ProgressUpdate {
-        hostname: hostname.to_string(),
-        phase: UpdatePhase::Connecting,
-        output_line: Some(format!("Connecting to {}...", ip)),
-    });
-
-    // Connect to server with timeout
-    let timeout = Duration::from_secs(60);
-    let addr = format!("{}:22", ip)
-        .to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("Failed to resolve address: {}", ip))?;
-
-    let tcp = TcpStream::connect_timeout(&addr, timeout)
-        .map_err(|e| anyhow::anyhow!("Connection timeout or failed after 60 seconds: {}", e))?;
-
-    // Set longer timeouts for read/write operations since builds can take a while
-    tcp.set_read_timeout(Some(Duration::from_secs(300)))?; // 5 minutes
-    tcp.set_write_timeout(Some(Duration::from_secs(300)))?; // 5 minutes
+    result.record_command(command, &buf, exit_status, started);
 
-    // Set up SSH session
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.set_timeout(300000); // 300 second (5 minute) timeout
-    sess.handshake()?;
-
-    // Keep blocking mode for all operations
-    // The session is already in blocking mode by default after handshake
-    sess.set_blocking(true);
-
-    // Authenticate
-    let username = "root";
-    let authenticated = authenticate_ssh_session(&sess, username, hostname, &progress_tx)?;
-
-    if !authenticated {
-        return Ok((
-            hostname.to_string(),
-            false,
-            "SSH authentication failed".to_string(),
-        ));
-    }
-
-    let mut output = String::new();
-    let mut success = true;
-
-    // Execute before-command if provided and run_after is false (default)
-    if !run_after {
-        if let Some(ref cmd) = command {
-            let _ = progress_tx.try_send(ProgressUpdate {
-                hostname: hostname.to_string(),
-                phase: UpdatePhase::RunningBeforeCommand,
-                output_line: Some(format!("Running: {}", cmd)),
-            });
+    Ok(exit_status)
+}
 
-            output.push_str(&format!("=== Running before-command ===\n"));
+/// Current commit of the configuration repository
+///
+/// Run as root since git refuses to work in a repository owned by someone else.
+fn query_git_revision(sess: &Session, forward_agent: bool, sudo: &Sudo) -> Option<String> {
+    let (rev, exit_status) = execute_privileged(
+        sess,
+        "git -C /etc/nixos rev-parse HEAD",
+        forward_agent,
+        sudo,
+    )
+    .ok()?;
+    (exit_status == 0).then(|| rev.trim().to_string())
+}
 
-            let (buf, exit_status) = execute_command_on_channel(&sess, cmd, forward_agent)?;
-            output.push_str(&format!("$ {}\n{}\n", cmd, buf));
+/// Commit of a local flake checkout, if it is a git repository
+fn local_git_revision(flake: &str) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .args(["-C", flake, "rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
 
-            if exit_status != 0 {
-                success = false;
-                let error_msg = format!("Before-command failed with exit code: {}", exit_status);
-                output.push_str(&error_msg);
-                output.push('\n');
+/// Number of the generation the system profile points to
+pub fn query_system_generation(sess: &Session, forward_agent: bool) -> Option<u64> {
+    let (link, _) =
+        execute_command_on_channel(sess, "readlink /nix/var/nix/profiles/system", forward_agent)
+            .ok()?;
+    // system-42-link
+    link.trim()
+        .strip_prefix("system-")?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
 
-                let _ = progress_tx.try_send(ProgressUpdate {
-                    hostname: hostname.to_string(),
-                    phase: UpdatePhase::Failed {
-                        reason: error_msg.clone(),
-                    },
-                    output_line: None,
-                });
+/// Parts of the activated system that only take effect after a reboot
+fn query_reboot_required(sess: &Session, forward_agent: bool) -> Vec<String> {
+    let check_cmd = "for f in kernel initrd kernel-modules systemd; do \
+         [ \"$(readlink -f /run/booted-system/$f)\" = \"$(readlink -f /run/current-system/$f)\" ] || echo $f; \
+         done";
+    execute_command_on_channel(sess, check_cmd, forward_agent)
+        .map(|(output, _)| output.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
 
-                return Ok((hostname.to_string(), success, output));
-            }
-        }
-    }
+/// Check out the latest configuration on the host and run nixos-rebuild there
+///
+/// Actions that don't add a generation build the fetched upstream revision
+/// instead, so the checkout in /etc/nixos stays as it is.
+///
+/// Returns the error message if a step failed.
+fn pull_and_rebuild(
+    sess: &Session,
+    host: &Host,
+    options: &UpdateOptions,
+    sudo: &Sudo,
+    watchdog: Option<&Watchdog>,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<Option<String>> {
+    let hostname = host.name.as_str();
+    let forward_agent = options.forward_agent;
 
     // Check git repo
     let _ = progress_tx.try_send(ProgressUpdate {
@@ -288,21 +481,31 @@ fn update_server_blocking(
     });
 
     let (git_check, _) = execute_command_on_channel(
-        &sess,
+        sess,
         "test -d /etc/nixos/.git || echo 'No git repo found'",
         forward_agent,
     )?;
 
     if git_check.contains("No git repo found") {
-        let error_msg = "No git repository found in /etc/nixos".to_string();
-        let _ = progress_tx.try_send(ProgressUpdate {
-            hostname: hostname.to_string(),
-            phase: UpdatePhase::Failed {
-                reason: error_msg.clone(),
-            },
-            output_line: None,
-        });
-        return Ok((hostname.to_string(), false, error_msg));
+        return Ok(Some("No git repository found in /etc/nixos".to_string()));
+    }
+
+    result.git_rev_before = query_git_revision(sess, forward_agent, sudo);
+
+    // Actions that don't change the system profile build the upstream revision
+    // as is, without moving the checkout
+    if !options.action.is_permanent() {
+        let revision =
+            match fetch_upstream_revision(sess, forward_agent, sudo, progress_tx, result)? {
+                Ok(revision) => revision,
+                Err(error_msg) => return Ok(Some(error_msg)),
+            };
+        let flake = format!(
+            "git+file:///etc/nixos?rev={}#{}",
+            revision,
+            host.flake_attribute()
+        );
+        return rebuild(sess, options, &flake, sudo, watchdog, progress_tx, result);
     }
 
     // Git pull
@@ -313,101 +516,700 @@ fn update_server_blocking(
     });
 
     let git_cmd = "cd /etc/nixos && git pull --verbose";
-    let (buf, exit_status) =
-        execute_command_streaming(&sess, git_cmd, forward_agent, &progress_tx, hostname, false)?;
-    output.push_str(&format!("$ {}\n{}\n", git_cmd, buf));
+    let exit_status = run_recorded(
+        sess,
+        git_cmd,
+        forward_agent,
+        sudo,
+        progress_tx,
+        result,
+        CommandOutput::Streamed {
+            phase: UpdatePhase::PullingGit,
+            use_pty: false,
+        },
+    )?;
 
     if exit_status != 0 {
-        success = false;
-        let error_msg = format!("Git pull failed with exit code: {}", exit_status);
-        output.push_str(&error_msg);
-        output.push('\n');
+        return Ok(Some(format!(
+            "Git pull failed with exit code: {}",
+            exit_status
+        )));
+    }
 
-        let _ = progress_tx.try_send(ProgressUpdate {
-            hostname: hostname.to_string(),
-            phase: UpdatePhase::Failed { reason: error_msg },
-            output_line: None,
-        });
+    result.git_rev_after = query_git_revision(sess, forward_agent, sudo);
 
-        return Ok((hostname.to_string(), success, output));
+    let flake = format!("/etc/nixos#{}", host.flake_attribute());
+    rebuild(sess, options, &flake, sudo, watchdog, progress_tx, result)
+}
+
+/// Fetch /etc/nixos and return the revision of its upstream branch, leaving
+/// the checkout as it is
+///
+/// Returns the failure reason if git fetch failed.
+pub fn fetch_upstream_revision(
+    sess: &Session,
+    forward_agent: bool,
+    sudo: &Sudo,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<std::result::Result<String, String>> {
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: result.hostname.clone(),
+        phase: UpdatePhase::PullingGit,
+        output_line: Some("Fetching configuration updates...".to_string()),
+    });
+
+    let started = Instant::now();
+    let fetch_cmd = "git -C /etc/nixos fetch --quiet && git -C /etc/nixos rev-parse '@{upstream}'";
+    let (output, exit_status) = execute_privileged(sess, fetch_cmd, forward_agent, sudo)?;
+    result.record_command(fetch_cmd, &output, exit_status, started);
+    if exit_status != 0 {
+        return Ok(Err(format!(
+            "git fetch failed with exit code: {}",
+            exit_status
+        )));
     }
 
-    // nixos-rebuild
+    let revision = output.trim().to_string();
+    result.git_rev_after = Some(revision.clone());
+    Ok(Ok(revision))
+}
+
+/// Run nixos-rebuild for `flake`, a flake reference with the host's attribute
+///
+/// Returns the error message if it failed.
+fn rebuild(
+    sess: &Session,
+    options: &UpdateOptions,
+    flake: &str,
+    sudo: &Sudo,
+    watchdog: Option<&Watchdog>,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<Option<String>> {
     let _ = progress_tx.try_send(ProgressUpdate {
-        hostname: hostname.to_string(),
+        hostname: result.hostname.clone(),
         phase: UpdatePhase::Rebuilding {
-            progress: String::new(),
+            progress: NixProgress::default(),
         },
         output_line: Some("Starting system rebuild...".to_string()),
     });
 
-    let rebuild_mode = if use_boot { "boot" } else { "switch" };
     let rebuild_cmd = format!(
-        "nixos-rebuild {} --flake \"/etc/nixos#{}\" --no-write-lock-file",
-        rebuild_mode, flake_hostname
+        "nixos-rebuild {} --flake \"{}\" --no-write-lock-file {}",
+        options.action,
+        flake,
+        LOG_FORMAT_ARGS.join(" ")
     );
+    // build leaves a ./result link behind; keep it out of the login user's home
+    let rebuild_cmd = if options.action == RebuildAction::Build {
+        format!(
+            "dir=$(mktemp -d) && cd \"$dir\" && {}; status=$?; rm -rf \"$dir\"; exit $status",
+            rebuild_cmd
+        )
+    } else {
+        rebuild_cmd
+    };
+    let rebuild_cmd = match watchdog {
+        Some(watchdog) => watchdog.track(&rebuild_cmd),
+        None => rebuild_cmd,
+    };
 
-    let (buf, exit_status) = execute_command_streaming(
-        &sess,
+    let exit_status = run_recorded(
+        sess,
         &rebuild_cmd,
-        forward_agent,
-        &progress_tx,
-        hostname,
-        true, // is_rebuild = true
+        options.forward_agent,
+        sudo,
+        progress_tx,
+        result,
+        CommandOutput::Streamed {
+            phase: UpdatePhase::Rebuilding {
+                progress: NixProgress::default(),
+            },
+            use_pty: true,
+        },
     )?;
-    output.push_str(&format!("$ {}\n{}\n", rebuild_cmd, buf));
 
     if exit_status != 0 {
-        success = false;
-        let error_msg = format!("nixos-rebuild failed with exit code: {}", exit_status);
-        output.push_str(&error_msg);
-        output.push('\n');
+        return Ok(Some(format!(
+            "nixos-rebuild failed with exit code: {}",
+            exit_status
+        )));
+    }
+
+    Ok(None)
+}
 
+/// Copy a locally built toplevel to the host and activate it
+///
+/// With `build`, the closure is only copied so the host has it ready.
+///
+/// Returns the error message if a step failed.
+fn push_and_activate(
+    sess: &Session,
+    toplevel: &str,
+    options: &UpdateOptions,
+    sudo: &Sudo,
+    watchdog: Option<&Watchdog>,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<Option<String>> {
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: result.hostname.clone(),
+        phase: UpdatePhase::CopyingClosure {
+            progress: String::new(),
+        },
+        output_line: Some(format!("Copying closure of {}...", toplevel)),
+    });
+
+    if let Err(e) = copy_closure(sess, toplevel, sudo, progress_tx, result) {
+        return Ok(Some(format!("Copying closure failed: {}", e)));
+    }
+
+    if options.action == RebuildAction::Build {
+        return Ok(None);
+    }
+
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: result.hostname.clone(),
+        phase: UpdatePhase::Activating,
+        output_line: Some("Activating new configuration...".to_string()),
+    });
+
+    // Only switch and boot add a generation to the system profile
+    let activate_cmd = if options.action.is_permanent() {
+        format!(
+            "nix-env -p /nix/var/nix/profiles/system --set {0} && {0}/bin/switch-to-configuration {1}",
+            toplevel, options.action
+        )
+    } else {
+        format!(
+            "{}/bin/switch-to-configuration {}",
+            toplevel, options.action
+        )
+    };
+    let activate_cmd = match watchdog {
+        Some(watchdog) => watchdog.track(&activate_cmd),
+        None => activate_cmd,
+    };
+
+    let exit_status = run_recorded(
+        sess,
+        &activate_cmd,
+        options.forward_agent,
+        sudo,
+        progress_tx,
+        result,
+        CommandOutput::Streamed {
+            phase: UpdatePhase::Activating,
+            use_pty: true,
+        },
+    )?;
+
+    if exit_status != 0 {
+        return Ok(Some(format!(
+            "Activation failed with exit code: {}",
+            exit_status
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Run the health checks that apply to the host, retrying each one
+///
+/// Returns a description of the first check that kept failing.
+fn run_health_checks(
+    sess: &Session,
+    host: &Host,
+    options: &UpdateOptions,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<Option<String>> {
+    let settings = &options.health;
+    let checks = settings.checks_for(host);
+    if checks.is_empty() {
+        return Ok(None);
+    }
+
+    for check in checks {
+        let description = check.description();
+        let command = check.command();
+        let attempts = settings.retries.max(1);
+
+        let mut passed = false;
+        for attempt in 1..=attempts {
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: host.name.clone(),
+                phase: UpdatePhase::HealthCheck,
+                output_line: Some(format!(
+                    "Checking {} (attempt {}/{})...",
+                    description, attempt, attempts
+                )),
+            });
+
+            let exit_status = run_recorded(
+                sess,
+                &command,
+                options.forward_agent,
+                &Sudo::Off,
+                progress_tx,
+                result,
+                CommandOutput::Streamed {
+                    phase: UpdatePhase::HealthCheck,
+                    use_pty: false,
+                },
+            )?;
+
+            if exit_status == 0 {
+                passed = true;
+                break;
+            }
+            if attempt < attempts {
+                std::thread::sleep(settings.interval());
+            }
+        }
+
+        if !passed {
+            return Ok(Some(format!("Health check failed: {}", description)));
+        }
+
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: host.name.clone(),
+            phase: UpdatePhase::HealthCheck,
+            output_line: Some(format!("✓ {}", description)),
+        });
+    }
+
+    Ok(None)
+}
+
+/// Switch the system profile back to `generation` and activate it
+pub fn switch_to_generation(
+    sess: &Session,
+    generation: u64,
+    use_boot: bool,
+    forward_agent: bool,
+    sudo: &Sudo,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+    result: &mut UpdateResult,
+) -> Result<i32> {
+    let _ = progress_tx.try_send(ProgressUpdate {
+        hostname: result.hostname.clone(),
+        phase: UpdatePhase::Activating,
+        output_line: Some(format!("Rolling back to generation {}...", generation)),
+    });
+
+    let activation = if use_boot { "boot" } else { "switch" };
+    let rollback_cmd = format!(
+        "nix-env -p /nix/var/nix/profiles/system --switch-generation {} && \
+         /nix/var/nix/profiles/system/bin/switch-to-configuration {}",
+        generation, activation
+    );
+
+    run_recorded(
+        sess,
+        &rollback_cmd,
+        forward_agent,
+        sudo,
+        progress_tx,
+        result,
+        CommandOutput::Streamed {
+            phase: UpdatePhase::Activating,
+            use_pty: true,
+        },
+    )
+}
+
+/// Open an authenticated SSH session to the host
+///
+/// Returns the failure reason if the host key was refused or authentication
+/// failed; it has already been sent to the TUI.
+pub fn connect_session(
+    host: &Host,
+    connect: &ConnectOptions,
+    connect_timeout: Duration,
+    progress_tx: &mpsc::Sender<ProgressUpdate>,
+) -> Result<std::result::Result<Session, String>> {
+    let hostname = host.name.as_str();
+    let ip = host.ssh_address();
+
+    let mut sess = Session::new()?;
+
+    match &host.proxy_jump {
+        Some(chain) => {
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::Connecting,
+                output_line: Some(format!("Connecting to {} via {}...", ip, chain)),
+            });
+
+            let stream = match connect.jumps.tunnel(
+                chain,
+                hostname,
+                ip,
+                host.ssh_port(),
+                connect,
+                progress_tx,
+            )? {
+                Ok(stream) => stream,
+                Err(error_msg) => return Ok(Err(error_msg)),
+            };
+            stream.set_read_timeout(Some(Duration::from_secs(300)))?;
+            stream.set_write_timeout(Some(Duration::from_secs(300)))?;
+            sess.set_tcp_stream(stream);
+        }
+        None => {
+            // Send connecting phase
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::Connecting,
+                output_line: Some(format!("Connecting to {}...", ip)),
+            });
+
+            // Connect to server with timeout
+            let addr = (ip, host.ssh_port())
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Failed to resolve address: {}", ip))?;
+
+            let tcp = TcpStream::connect_timeout(&addr, connect_timeout).map_err(|e| {
+                anyhow::anyhow!(
+                    "Connection timeout or failed after {} seconds: {}",
+                    connect_timeout.as_secs(),
+                    e
+                )
+            })?;
+
+            // Set longer timeouts for read/write operations since builds can take a while
+            tcp.set_read_timeout(Some(Duration::from_secs(300)))?; // 5 minutes
+            tcp.set_write_timeout(Some(Duration::from_secs(300)))?; // 5 minutes
+            sess.set_tcp_stream(tcp);
+        }
+    }
+
+    // Set up SSH session
+    sess.set_timeout(300000); // 300 second (5 minute) timeout
+    if connect.host_key_checking != HostKeyChecking::Off {
+        prefer_known_key_types(&sess, ip, host.ssh_port())?;
+    }
+    sess.handshake()?;
+
+    if let Err((reason, details)) =
+        verify_host_key(&sess, ip, host.ssh_port(), connect.host_key_checking)?
+    {
         let _ = progress_tx.try_send(ProgressUpdate {
             hostname: hostname.to_string(),
-            phase: UpdatePhase::Failed { reason: error_msg },
-            output_line: None,
+            phase: UpdatePhase::Failed {
+                reason: reason.clone(),
+            },
+            output_line: Some(details.clone()),
         });
+        return Ok(Err(details));
+    }
 
-        return Ok((hostname.to_string(), success, output));
+    // Keep blocking mode for all operations
+    // The session is already in blocking mode by default after handshake
+    sess.set_blocking(true);
+
+    if let Some(interval) = host.server_alive_interval {
+        // Only sent from `Keepalive::tick` in the loops that wait on a channel
+        sess.set_keepalive(false, interval.min(u32::MAX as u64) as u32);
     }
 
-    // Execute after-command if provided, run_after is true, and previous commands succeeded
-    if success && run_after {
-        if let Some(ref cmd) = command {
+    // Authenticate
+    let authenticated = authenticate_ssh_session(&sess, host, &connect.passphrases, progress_tx)?;
+    if !authenticated {
+        return Ok(Err("SSH authentication failed".to_string()));
+    }
+
+    Ok(Ok(sess))
+}
+
+fn update_server_blocking(
+    host: &Host,
+    options: &UpdateOptions,
+    progress_tx: mpsc::Sender<ProgressUpdate>,
+) -> Result<UpdateResult> {
+    let hostname = host.name.as_str();
+    let forward_agent = options.forward_agent;
+    let sudo = Sudo::for_host(host, options.sudo_password.as_deref());
+    let mut result = UpdateResult::new(hostname);
+
+    // Build before connecting so the SSH session doesn't sit idle during long builds
+    let toplevel = match &options.local_build {
+        Some(local_build) => {
             let _ = progress_tx.try_send(ProgressUpdate {
                 hostname: hostname.to_string(),
-                phase: UpdatePhase::RunningAfterCommand,
-                output_line: Some(format!("Running: {}", cmd)),
+                phase: UpdatePhase::Building {
+                    progress: NixProgress::default(),
+                },
+                output_line: Some(format!(
+                    "Building {}#{}...",
+                    local_build.flake,
+                    host.flake_attribute()
+                )),
             });
 
-            output.push_str(&format!("=== Running after-command ===\n"));
-            let (buf, exit_status) = execute_command_on_channel(&sess, cmd, forward_agent)?;
-            output.push_str(&format!("$ {}\n{}\n", cmd, buf));
+            result.git_rev_after = local_git_revision(&local_build.flake);
 
-            if exit_status != 0 {
-                success = false;
-                let error_msg = format!("After-command failed with exit code: {}", exit_status);
-                output.push_str(&error_msg);
-                output.push('\n');
+            // Nothing to copy or activate, the host isn't needed
+            if options.action == RebuildAction::DryBuild {
+                return Ok(
+                    match local_build.dry_build(host.flake_attribute(), &progress_tx, &mut result) {
+                        Ok(()) => {
+                            let _ = progress_tx.try_send(ProgressUpdate {
+                                hostname: hostname.to_string(),
+                                phase: UpdatePhase::Success {
+                                    reboot_required: Vec::new(),
+                                    rollback_to: None,
+                                },
+                                output_line: None,
+                            });
+                            result.success = true;
+                            result
+                        }
+                        Err(e) => {
+                            let error_msg = format!("Local dry build failed: {}", e);
+                            result.fail(error_msg, &progress_tx)
+                        }
+                    },
+                );
+            }
 
-                let _ = progress_tx.try_send(ProgressUpdate {
-                    hostname: hostname.to_string(),
-                    phase: UpdatePhase::Failed { reason: error_msg },
-                    output_line: None,
-                });
+            match local_build.build_toplevel(host.flake_attribute(), &progress_tx, &mut result) {
+                Ok(toplevel) => Some(toplevel),
+                Err(e) => {
+                    let error_msg = format!("Local build failed: {}", e);
+                    return Ok(result.fail(error_msg, &progress_tx));
+                }
+            }
+        }
+        None => None,
+    };
 
-                return Ok((hostname.to_string(), success, output));
+    let mut sess = match connect_session(
+        host,
+        &options.connect,
+        Duration::from_secs(60),
+        &progress_tx,
+    )? {
+        Ok(sess) => sess,
+        Err(error_msg) => return Ok(result.fail(error_msg, &progress_tx)),
+    };
+
+    // Execute before-command if provided and run_after is false (default)
+    if !options.run_after
+        && let Some(ref cmd) = options.command
+    {
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: hostname.to_string(),
+            phase: UpdatePhase::RunningBeforeCommand,
+            output_line: Some(format!("Running: {}", cmd)),
+        });
+
+        result.output.push_str("=== Running before-command ===\n");
+
+        let exit_status = run_recorded(
+            &sess,
+            cmd,
+            forward_agent,
+            &Sudo::Off,
+            &progress_tx,
+            &mut result,
+            CommandOutput::Buffered,
+        )?;
+
+        if exit_status != 0 {
+            let error_msg = format!("Before-command failed with exit code: {}", exit_status);
+            return Ok(result.fail(error_msg, &progress_tx));
+        }
+    }
+
+    // Only actions that change the running system can cut us off or be health
+    // checked. The watchdog rolls back to the previous generation, so it needs switch.
+    let magic_rollback = options
+        .magic_rollback
+        .filter(|_| options.action == RebuildAction::Switch);
+    let reboot = options
+        .reboot
+        .filter(|_| options.action == RebuildAction::Boot);
+    let run_health_checks_after = (options.action.activates() || reboot.is_some())
+        && !options.health.checks_for(host).is_empty();
+    let needs_previous_generation =
+        magic_rollback.is_some() || (options.health.rollback && run_health_checks_after);
+
+    if options.action.is_permanent() || needs_previous_generation {
+        result.previous_generation = query_system_generation(&sess, forward_agent);
+    }
+    let previous_generation = if needs_previous_generation {
+        let Some(generation) = result.previous_generation else {
+            let error_msg = "Cannot determine the current generation to roll back to".to_string();
+            return Ok(result.fail(error_msg, &progress_tx));
+        };
+        Some(generation)
+    } else {
+        None
+    };
+
+    let watchdog = match (magic_rollback, previous_generation) {
+        (Some(timeout), Some(generation)) => Some(Watchdog::arm(
+            &sess,
+            hostname,
+            forward_agent,
+            &sudo,
+            generation,
+            timeout,
+        )?),
+        _ => None,
+    };
+
+    let output_start = result.output.len();
+    let failure = match &toplevel {
+        Some(toplevel) => push_and_activate(
+            &sess,
+            toplevel,
+            options,
+            &sudo,
+            watchdog.as_ref(),
+            &progress_tx,
+            &mut result,
+        )?,
+        None => pull_and_rebuild(
+            &sess,
+            host,
+            options,
+            &sudo,
+            watchdog.as_ref(),
+            &progress_tx,
+            &mut result,
+        )?,
+    };
+
+    if let Some(error_msg) = failure {
+        if let Some(watchdog) = &watchdog {
+            watchdog.disarm(&sess, forward_agent, &sudo);
+        }
+        return Ok(result.fail(error_msg, &progress_tx));
+    }
+
+    if let Some(watchdog) = &watchdog {
+        match watchdog.confirm(host, &options.connect, forward_agent, &sudo, &progress_tx)? {
+            Ok(new_sess) => sess = new_sess,
+            Err(error_msg) => return Ok(result.fail(error_msg, &progress_tx)),
+        }
+    }
+
+    result.generation = query_system_generation(&sess, forward_agent);
+
+    if options.action == RebuildAction::DryActivate {
+        let changes = DryActivation::parse(&result.output[output_start..]);
+        for line in changes.summary_lines() {
+            let _ = progress_tx.try_send(ProgressUpdate {
+                hostname: hostname.to_string(),
+                phase: UpdatePhase::Activating,
+                output_line: Some(format!("Dry activation: {}", line)),
+            });
+        }
+        result.dry_activation = Some(changes);
+    }
+
+    if let Some(timeout) = reboot {
+        match reboot_and_wait(
+            &sess,
+            host,
+            &options.connect,
+            forward_agent,
+            &sudo,
+            timeout,
+            &progress_tx,
+            &mut result,
+        )? {
+            Ok(new_sess) => sess = new_sess,
+            Err(error_msg) => return Ok(result.fail(error_msg, &progress_tx)),
+        }
+    }
+
+    if run_health_checks_after
+        && let Some(error_msg) = run_health_checks(&sess, host, options, &progress_tx, &mut result)?
+    {
+        let error_msg = match previous_generation.filter(|_| options.health.rollback) {
+            Some(generation) => {
+                let exit_status = switch_to_generation(
+                    &sess,
+                    generation,
+                    options.action == RebuildAction::Boot,
+                    forward_agent,
+                    &sudo,
+                    &progress_tx,
+                    &mut result,
+                )?;
+                if exit_status == 0 {
+                    result.generation = Some(generation);
+                    format!("{}; rolled back to generation {}", error_msg, generation)
+                } else {
+                    format!(
+                        "{}; rollback to generation {} failed with exit code: {}",
+                        error_msg, generation, exit_status
+                    )
+                }
             }
+            None => error_msg,
+        };
+        return Ok(result.fail(error_msg, &progress_tx));
+    }
+
+    // Execute after-command if provided, run_after is true, and previous commands succeeded
+    if options.run_after
+        && let Some(ref cmd) = options.command
+    {
+        let _ = progress_tx.try_send(ProgressUpdate {
+            hostname: hostname.to_string(),
+            phase: UpdatePhase::RunningAfterCommand,
+            output_line: Some(format!("Running: {}", cmd)),
+        });
+
+        result.output.push_str("=== Running after-command ===\n");
+        let exit_status = run_recorded(
+            &sess,
+            cmd,
+            forward_agent,
+            &Sudo::Off,
+            &progress_tx,
+            &mut result,
+            CommandOutput::Buffered,
+        )?;
+
+        if exit_status != 0 {
+            let error_msg = format!("After-command failed with exit code: {}", exit_status);
+            return Ok(result.fail(error_msg, &progress_tx));
         }
     }
 
+    if options.action.activates() {
+        result.reboot_required = query_reboot_required(&sess, forward_agent);
+    }
+
+    // Only offer a rollback if there is a new generation to roll back from
+    let rollback_to = result.previous_generation.filter(|&previous| {
+        options.action.is_permanent() && result.generation.is_some_and(|g| g != previous)
+    });
+
     // Success!
     let _ = progress_tx.try_send(ProgressUpdate {
         hostname: hostname.to_string(),
-        phase: UpdatePhase::Success,
-        output_line: None,
+        phase: UpdatePhase::Success {
+            reboot_required: result.reboot_required.clone(),
+            rollback_to,
+        },
+        output_line: (!result.reboot_required.is_empty()).then(|| {
+            format!(
+                "Reboot required: {} changed",
+                result.reboot_required.join(", ")
+            )
+        }),
     });
 
-    Ok((hostname.to_string(), success, output))
+    result.success = true;
+    Ok(result)
 }